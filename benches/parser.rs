@@ -0,0 +1,62 @@
+// Compares parsing into owned and borrowed addresses, run with `cargo bench`
+
+extern crate openssl;
+extern crate rustsrsd;
+
+use openssl::hash::MessageDigest;
+
+use rustsrsd::srs::parser::{SRSAddress,SRSAddressRef};
+use rustsrsd::srs::transformers::{Forwarder,Receiver,SRSTimestamper,ForwardableAddress};
+
+use std::time::Instant;
+
+const ITERATIONS: u32 = 1000000;
+
+fn bench<F>(name: &str, iterations: u32, f: F) where F: Fn() -> usize {
+    let start = Instant::now();
+    let mut sum = 0;
+    for _ in 0..iterations {
+        sum += f();
+    }
+    let elapsed = start.elapsed();
+    let nanos = elapsed.as_secs() * 1000000000 + elapsed.subsec_nanos() as u64;
+    // print sum so the work cannot be optimized away
+    println!("{:<24} {:>8} ns/iter (checksum {})", name, nanos / iterations as u64, sum);
+}
+
+fn main() {
+
+    let secret = b"tops3cr3t".to_vec();
+    let f = Forwarder::new(secret.clone(), b"forwarder.example.com".to_vec(), MessageDigest::sha512(), "=",
+                           SRSTimestamper::new(21))
+        .expect("valid forwarder params");
+    let r = Receiver::new(secret, b"forwarder.example.com".to_vec(), MessageDigest::sha512(),
+                          SRSTimestamper::new(21))
+        .expect("valid receiver params");
+
+    let srs0 = f.forward(ForwardableAddress::Plain{
+        local: "some.user".to_string(),
+        domain: "origin.example.org".to_string(),
+    }).expect("forwarding works").to_string();
+    let srs1 = "SRS1=GGGG=orig.hostname==HHHH=TT=orig-domain-part=orig-local-part@domain-part";
+
+    for &(name, address) in &[("srs0", srs0.as_str()), ("srs1", srs1)] {
+        bench(&format!("parse owned {}", name), ITERATIONS, || {
+            SRSAddress::from_string(address).unwrap().domain().len()
+        });
+        bench(&format!("parse borrowed {}", name), ITERATIONS, || {
+            match SRSAddressRef::from_string(address).unwrap() {
+                SRSAddressRef::SRS0(a) => a.domain.len(),
+                SRSAddressRef::SRS1(a) => a.domain.len(),
+            }
+        });
+    }
+
+    bench("receive owned", ITERATIONS / 10, || {
+        r.receive(&SRSAddress::from_string(&srs0).unwrap()).unwrap().len()
+    });
+    bench("receive borrowed", ITERATIONS / 10, || {
+        r.receive_ref(&SRSAddressRef::from_string(&srs0).unwrap()).unwrap().len()
+    });
+
+}
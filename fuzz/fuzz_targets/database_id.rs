@@ -0,0 +1,17 @@
+// Run with `cargo fuzz run database_id`, every reverse lookup tries it if a database is configured
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate rustsrsd;
+
+use std::str;
+
+use rustsrsd::srs::database::parse_id;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(s) = str::from_utf8(data) {
+        if let Some(id) = parse_id(s) {
+            assert!(s[5..].starts_with(id));
+        }
+    }
+});
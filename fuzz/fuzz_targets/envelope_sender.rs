@@ -0,0 +1,20 @@
+// Run with `cargo fuzz run envelope_sender`, every forward lookup parses its key with it
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate rustsrsd;
+
+use std::str;
+
+use rustsrsd::srs::envelope::parse_envelope_sender;
+use rustsrsd::srs::parser::Charset;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(s) = str::from_utf8(data) {
+        for &charset in &[Charset::ASCII, Charset::SMTPUTF8] {
+            if let Ok(a) = parse_envelope_sender(s, charset) {
+                let _ = a.to_string();
+            }
+        }
+    }
+});
@@ -0,0 +1,19 @@
+// Run with `cargo fuzz run from_string`, the parser must never panic
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate rustsrsd;
+
+use std::str;
+
+use rustsrsd::srs::parser::{SRSAddress,Charset};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(s) = str::from_utf8(data) {
+        for &charset in &[Charset::ASCII, Charset::SMTPUTF8] {
+            if let Ok(a) = SRSAddress::parse(s, charset) {
+                assert!(a.to_string() == s);
+            }
+        }
+    }
+});
@@ -0,0 +1,731 @@
+/* rustsrsd configuration file
+ *
+ * A TOML subset: `key = value` pairs, optionally grouped by `[section]` headers.
+ * Values are "strings", integers, true / false or one-line arrays of strings.
+ *
+ *   domain = "srs.example.com"
+ *   secret_files = ["/etc/rustsrsd/secret"]
+ *
+ *   [listen]
+ *   recv = "unix:/var/spool/postfix/private/srs-recv"
+ *   send = "127.0.0.1:10002"
+ *
+ *   [policy]
+ *   local_domains = ["example.com", ".example.org"]
+ *   srs_domains = ["lists.example.net = srs.example.net"]
+ *
+ * Keys are addressed as `section.key` (e.g. `listen.recv`), which is also how
+ * command line flags override them. Lookups only know the recipient domain, which
+ * `srs_domains` matches, through the socketmap map `forward@lists.example.net`.
+ */
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use openssl::hash::MessageDigest;
+
+use daemon::listener::UnixSocketPermissions;
+use srs::parser::Charset;
+use srs::syntax;
+use srs::transformers::{is_valid_srs_separator,MIN_HASH_LENGTH};
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    List(Vec<String>),
+}
+
+#[derive(Debug,PartialEq)]
+pub struct ConfigError {
+    /// 1-based line in the configuration file, `None` for command line overrides
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(l) => write!(f, "line {}: {}", l, self.message),
+            None    => write!(f, "{}", self.message),
+        }
+    }
+}
+
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct ListenConfig {
+    pub recv: Option<String>,
+    pub send: Option<String>,
+    pub socketmap: Option<String>,
+}
+
+/// Rules of the forwarding policy, see `srs::policy`
+#[derive(Clone,Debug,PartialEq)]
+pub struct PolicyConfig {
+    /// Domains whose SPF record already covers us, their senders are not rewritten
+    pub local_domains: Vec<String>,
+    /// Sender domains that publish no SPF record, rewriting them gains nothing
+    pub no_spf_domains: Vec<String>,
+    pub skip_postmaster: bool,
+    /// (recipient domain, SRS domain) from `"recipient = srs"` entries
+    pub srs_domains: Vec<(String, String)>,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> PolicyConfig {
+        return PolicyConfig{
+            local_domains: Vec::new(),
+            no_spf_domains: Vec::new(),
+            skip_postmaster: true,
+            srs_domains: Vec::new(),
+        };
+    }
+}
+
+#[derive(Clone,Debug)]
+pub struct Config {
+    pub domain: Option<String>,
+    pub separator: String,
+    /// "guarded", "shortcut" or "reversible", see `SRSScheme`
+    pub scheme: String,
+    /// Verify SRS1 addresses of our domain and re-sign foreign ones when forwarding
+    pub strict_srs1: bool,
+    /// "native" or "libsrs2" to issue and accept addresses interchangeably with postsrsd
+    pub compatibility: String,
+    /// Ignored in libsrs2 compatibility mode, which always uses sha1
+    pub hash_algorithm: String,
+    /// Hash lowercased addresses, for MTAs that change the case of the envelope sender
+    pub fold_case: bool,
+    /// Accept UTF-8 addresses (RFC 6531), otherwise non-ASCII senders are left alone
+    pub smtputf8: bool,
+    /// "base64" or "base32", which unlike base64 survives a change of case
+    pub hash_encoding: String,
+    pub hash_length: usize,
+    /// Shortest hash accepted when receiving, `None` accepts only `hash_length`
+    pub hash_min_length: Option<usize>,
+    /// "days", "hours" or "none" for addresses that never expire, libsrs2 compatibility requires days
+    pub timestamp: String,
+    /// Timestamp validity window in days, or hours
+    pub max_age: u16,
+    /// Days (or hours) a timestamp may lie in the future, ignored by the libsrs2 format
+    pub max_future_skew: u16,
+    /// postsrsd-style secrets files, concatenated in order: the first secret signs, all verify
+    pub secret_files: Vec<PathBuf>,
+    /// Superseded by `policy.local_domains`, both are honoured
+    pub exclude_domains: Vec<String>,
+    pub policy: PolicyConfig,
+    /// "fail", "compress" or "database" for senders whose SRS local part would exceed 64 octets,
+    /// "compress" shortens the hash down to `hash_min_length`, which must be lower than `hash_length`
+    pub overflow_strategy: String,
+    /// Directory of the sender database, required by the "database" overflow strategy
+    pub database_dir: Option<PathBuf>,
+    pub listen: ListenConfig,
+    pub unix: UnixSocketPermissions,
+    pub max_request_len: usize,
+    pub max_connections: usize,
+    /// Seconds, 0 disables the timeout
+    pub idle_timeout: u64,
+    /// Line each key was set on in the configuration file, command line overrides are absent
+    pub lines: HashMap<String, usize>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        return Config{
+            domain: None,
+            separator: "=".to_string(),
+            scheme: "guarded".to_string(),
+            strict_srs1: false,
+            compatibility: "native".to_string(),
+            hash_algorithm: "sha512".to_string(),
+            fold_case: false,
+            smtputf8: false,
+            hash_encoding: "base64".to_string(),
+            hash_length: 4,
+            hash_min_length: None,
+            timestamp: "days".to_string(),
+            max_age: 21,
+            max_future_skew: 1,
+            secret_files: Vec::new(),
+            exclude_domains: Vec::new(),
+            policy: PolicyConfig::default(),
+            overflow_strategy: "fail".to_string(),
+            database_dir: None,
+            listen: ListenConfig::default(),
+            unix: UnixSocketPermissions::default(),
+            max_request_len: 4096,
+            max_connections: 100,
+            idle_timeout: 300,
+            lines: HashMap::new(),
+        };
+    }
+}
+
+pub fn message_digest(name: &str) -> Option<MessageDigest> {
+    match name {
+        "sha1"   => Some(MessageDigest::sha1()),
+        "sha224" => Some(MessageDigest::sha224()),
+        "sha256" => Some(MessageDigest::sha256()),
+        "sha384" => Some(MessageDigest::sha384()),
+        "sha512" => Some(MessageDigest::sha512()),
+        _        => None,
+    }
+}
+
+fn parse_string(s: &str) -> Result<(String, &str), String> {
+    assert!(s.starts_with("\""));
+    let mut out = String::new();
+    let mut chars = s[1..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"'  => return Ok((out, &s[1+i+1..])),
+            '\\' => match chars.next() {
+                Some((_, '"'))  => out.push('"'),
+                Some((_, '\\')) => out.push('\\'),
+                Some((_, 'n'))  => out.push('\n'),
+                Some((_, 't'))  => out.push('\t'),
+                Some((_, x))    => return Err(format!("invalid escape sequence \\{}", x)),
+                None            => break,
+            },
+            c    => out.push(c),
+        }
+    }
+    return Err("unterminated string".to_string());
+}
+
+fn strip_comment(rest: &str) -> Result<(), String> {
+    let rest = rest.trim();
+    if rest.is_empty() || rest.starts_with("#") {
+        return Ok(());
+    }
+    return Err(format!("unexpected trailing characters {:?}", rest));
+}
+
+fn parse_value(s: &str) -> Result<Value, String> {
+
+    if s.starts_with("\"") {
+        let (v, rest) = try!(parse_string(s));
+        try!(strip_comment(rest));
+        return Ok(Value::Str(v));
+    }
+
+    if s.starts_with("[") {
+        let mut items = Vec::new();
+        let mut rest = s[1..].trim_left();
+        loop {
+            if rest.starts_with("]") {
+                try!(strip_comment(&rest[1..]));
+                return Ok(Value::List(items));
+            }
+            if !rest.starts_with("\"") {
+                return Err("arrays may only contain strings and must be closed on the same line".to_string());
+            }
+            let (item, r) = try!(parse_string(rest));
+            items.push(item);
+            rest = r.trim_left();
+            if rest.starts_with(",") {
+                rest = rest[1..].trim_left();
+            } else if !rest.starts_with("]") {
+                return Err("expected ',' or ']' in array".to_string());
+            }
+        }
+    }
+
+    let token = match s.find("#") {
+        Some(pos) => s[..pos].trim(),
+        None      => s.trim(),
+    };
+    match token {
+        "true"  => return Ok(Value::Bool(true)),
+        "false" => return Ok(Value::Bool(false)),
+        _       => (),
+    }
+    return token.parse().map(Value::Int).map_err(|_| format!("invalid value {:?}", token));
+}
+
+fn is_bare_key(k: &str) -> bool {
+    !k.is_empty() && k.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Split a configuration file into (line, `section.key`, value) entries.
+pub fn parse_entries(text: &str) -> Result<Vec<(usize, String, Value)>, ConfigError> {
+
+    let mut entries: Vec<(usize, String, Value)> = Vec::new();
+    let mut section: Option<String> = None;
+
+    for (idx, raw) in text.lines().enumerate() {
+        let line = idx + 1;
+        let err = |m: String| ConfigError{ line: Some(line), message: m };
+        let l = raw.trim();
+
+        if l.is_empty() || l.starts_with("#") {
+            continue;
+        }
+
+        if l.starts_with("[") {
+            let end = try!(l.find("]").ok_or(err("unterminated section header".to_string())));
+            let name = l[1..end].trim();
+            if !is_bare_key(name) {
+                return Err(err(format!("invalid section name {:?}", name)));
+            }
+            try!(strip_comment(&l[end+1..]).map_err(&err));
+            section = Some(name.to_string());
+            continue;
+        }
+
+        let eq = try!(l.find("=").ok_or(err("expected `key = value`".to_string())));
+        let key = l[..eq].trim();
+        if !is_bare_key(key) {
+            return Err(err(format!("invalid key {:?}", key)));
+        }
+        let key = match section {
+            Some(ref s) => format!("{}.{}", s, key),
+            None        => key.to_string(),
+        };
+        if let Some(&(prev, _, _)) = entries.iter().find(|e| e.1 == key) {
+            return Err(err(format!("duplicate key {:?}, first set on line {}", key, prev)));
+        }
+
+        let value = try!(parse_value(l[eq+1..].trim()).map_err(&err));
+        entries.push((line, key, value));
+    }
+
+    return Ok(entries);
+}
+
+fn expect_str(v: Value) -> Result<String, String> {
+    match v {
+        Value::Str(s) => Ok(s),
+        x             => Err(format!("expected a string, got {:?}", x)),
+    }
+}
+
+fn expect_bool(v: Value) -> Result<bool, String> {
+    match v {
+        Value::Bool(b) => Ok(b),
+        x              => Err(format!("expected true or false, got {:?}", x)),
+    }
+}
+
+fn expect_list(v: Value) -> Result<Vec<String>, String> {
+    match v {
+        Value::List(l) => Ok(l),
+        Value::Str(s)  => Ok(vec![s]),
+        x              => Err(format!("expected an array of strings, got {:?}", x)),
+    }
+}
+
+// The HMAC-SHA1 digest (20 bytes) fills 6 groups of 4 base64 chars
+const MAX_HASH_LENGTH: u64 = 24;
+
+fn expect_uint(v: Value, max: u64) -> Result<u64, String> {
+    match v {
+        Value::Int(i) if i >= 0 && i as u64 <= max => Ok(i as u64),
+        Value::Int(i) => Err(format!("{} is out of range [0, {}]", i, max)),
+        x             => Err(format!("expected an integer, got {:?}", x)),
+    }
+}
+
+impl Config {
+
+    /// Parse a configuration file on top of the defaults.
+    pub fn from_str(text: &str) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        for (line, key, value) in try!(parse_entries(text)) {
+            try!(config.set(&key, value).map_err(|m| ConfigError{ line: Some(line), message: m }));
+            config.lines.insert(key, line);
+        }
+        return Ok(config);
+    }
+
+    /// Set a single key, validating the value.
+    pub fn set(&mut self, key: &str, value: Value) -> Result<(), String> {
+        match key {
+            "domain" => {
+                let d = try!(expect_str(value));
+                if !syntax::is_valid_domain(&d, Charset::ASCII) {
+                    return Err(format!("invalid domain {:?}", d));
+                }
+                if d.contains(self.separator.as_str()) {
+                    return Err(format!("domain {:?} must not contain the separator {:?}", d, self.separator));
+                }
+                self.domain = Some(d);
+            },
+            "separator" => {
+                let s = try!(expect_str(value));
+                if !is_valid_srs_separator(&s) {
+                    return Err(format!("invalid separator {:?}, must be one of \"=\", \"+\", \"-\"", s));
+                }
+                self.separator = s;
+            },
+            "scheme" => {
+                let c = try!(expect_str(value));
+                if c != "guarded" && c != "shortcut" && c != "reversible" {
+                    return Err(format!("unknown SRS scheme {:?}, must be \"guarded\", \"shortcut\" or \"reversible\"", c));
+                }
+                self.scheme = c;
+            },
+            "compatibility" => {
+                let c = try!(expect_str(value));
+                if c != "native" && c != "libsrs2" {
+                    return Err(format!("unknown compatibility mode {:?}, must be \"native\" or \"libsrs2\"", c));
+                }
+                self.compatibility = c;
+            },
+            "hash_algorithm" => {
+                let h = try!(expect_str(value));
+                if message_digest(&h).is_none() {
+                    return Err(format!("unsupported hash algorithm {:?}", h));
+                }
+                self.hash_algorithm = h;
+            },
+            "fold_case" => {
+                self.fold_case = try!(expect_bool(value));
+            },
+            "smtputf8" => {
+                self.smtputf8 = try!(expect_bool(value));
+            },
+            "strict_srs1" => {
+                self.strict_srs1 = try!(expect_bool(value));
+            },
+            "hash_encoding" => {
+                let e = try!(expect_str(value));
+                if e != "base64" && e != "base32" {
+                    return Err(format!("unknown hash encoding {:?}, must be \"base64\" or \"base32\"", e));
+                }
+                self.hash_encoding = e;
+            },
+            "hash_length" => {
+                let l = try!(expect_uint(value, MAX_HASH_LENGTH));
+                if (l as usize) < MIN_HASH_LENGTH {
+                    return Err(format!("hash_length must be at least {}", MIN_HASH_LENGTH));
+                }
+                self.hash_length = l as usize;
+            },
+            "hash_min_length" => {
+                let l = try!(expect_uint(value, MAX_HASH_LENGTH));
+                if (l as usize) < MIN_HASH_LENGTH {
+                    return Err(format!("hash_min_length must be at least {}", MIN_HASH_LENGTH));
+                }
+                self.hash_min_length = Some(l as usize);
+            },
+            "timestamp" => {
+                let t = try!(expect_str(value));
+                if t != "days" && t != "hours" && t != "none" {
+                    return Err(format!("unknown timestamp resolution {:?}, must be \"days\", \"hours\" or \"none\"", t));
+                }
+                self.timestamp = t;
+            },
+            "max_age" => {
+                // timestamps wrap after 1024 days
+                self.max_age = try!(expect_uint(value, 1023)) as u16;
+            },
+            "max_future_skew" => {
+                self.max_future_skew = try!(expect_uint(value, 1023)) as u16;
+            },
+            "secret_files" => {
+                let files = try!(expect_list(value));
+                self.secret_files = files.into_iter().map(PathBuf::from).collect();
+            },
+            "overflow_strategy" => {
+                let o = try!(expect_str(value));
+                if o != "fail" && o != "compress" && o != "database" {
+                    return Err(format!("unknown overflow strategy {:?}, must be \"fail\", \"compress\" or \"database\"", o));
+                }
+                self.overflow_strategy = o;
+            },
+            "database_dir" => {
+                self.database_dir = Some(PathBuf::from(try!(expect_str(value))));
+            },
+            "exclude_domains" => {
+                self.exclude_domains = try!(expect_list(value));
+            },
+            "policy.local_domains" => self.policy.local_domains = try!(expect_list(value)),
+            "policy.no_spf_domains" => self.policy.no_spf_domains = try!(expect_list(value)),
+            "policy.skip_postmaster" => self.policy.skip_postmaster = try!(expect_bool(value)),
+            "policy.srs_domains" => {
+                let mut srs_domains = Vec::new();
+                for entry in try!(expect_list(value)) {
+                    let (recipient, srs) = match entry.find('=') {
+                        Some(eq) => (entry[..eq].trim(), entry[eq+1..].trim()),
+                        None     => return Err(format!("expected \"recipient = srs\" domains, got {:?}", entry)),
+                    };
+                    for d in &[recipient, srs] {
+                        if !syntax::is_valid_domain(d, Charset::ASCII) {
+                            return Err(format!("invalid domain {:?}", d));
+                        }
+                    }
+                    srs_domains.push((recipient.to_string(), srs.to_string()));
+                }
+                self.policy.srs_domains = srs_domains;
+            },
+            "listen.recv" => self.listen.recv = Some(try!(expect_str(value))),
+            "listen.send" => self.listen.send = Some(try!(expect_str(value))),
+            "listen.socketmap" => self.listen.socketmap = Some(try!(expect_str(value))),
+            "unix.owner" => self.unix.owner = Some(try!(expect_str(value))),
+            "unix.group" => self.unix.group = Some(try!(expect_str(value))),
+            "unix.mode" => {
+                let m = try!(expect_str(value));
+                let mode = try!(u32::from_str_radix(&m, 8).map_err(|_| format!("invalid octal mode {:?}", m)));
+                if mode > 0o7777 {
+                    return Err(format!("invalid octal mode {:?}", m));
+                }
+                self.unix.mode = Some(mode);
+            },
+            "max_request_len" => self.max_request_len = try!(expect_uint(value, 1 << 20)) as usize,
+            "max_connections" => self.max_connections = try!(expect_uint(value, 1 << 16)) as usize,
+            "idle_timeout" => self.idle_timeout = try!(expect_uint(value, 1 << 32)),
+            _ => return Err(format!("unknown key {:?}", key)),
+        }
+        return Ok(());
+    }
+
+    /// Set a key from its command line representation, e.g. `--max-age 5`.
+    pub fn set_from_cli(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let value = match key {
+            "hash_length" | "hash_min_length" | "max_age" | "max_future_skew" | "max_request_len" | "max_connections" | "idle_timeout" => {
+                match value.parse() {
+                    Ok(i)  => Value::Int(i),
+                    Err(_) => return Err(ConfigError{ line: None, message: format!("{}: expected an integer, got {:?}", key, value) }),
+                }
+            },
+            "fold_case" | "strict_srs1" | "smtputf8" => {
+                match value {
+                    "true"  => Value::Bool(true),
+                    "false" => Value::Bool(false),
+                    _       => return Err(ConfigError{ line: None, message: format!("{}: expected true or false, got {:?}", key, value) }),
+                }
+            },
+            _ => Value::Str(value.to_string()),
+        };
+        try!(self.set(key, value).map_err(|m| ConfigError{ line: None, message: format!("{}: {}", key, m) }));
+        self.lines.remove(key);
+        return Ok(());
+    }
+
+    /// Line of whichever of `keys` was set last in the configuration file, `None` if
+    /// none was set there.
+    fn line_of(&self, keys: &[&str]) -> Option<usize> {
+        return keys.iter().filter_map(|k| self.lines.get(*k)).cloned().max();
+    }
+
+    /// Check settings that cannot be validated key by key. Conflicts point to the
+    /// later of the lines involved.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let err = |keys: &[&str], m: &str| Err(ConfigError{ line: self.line_of(keys), message: m.to_string() });
+        if self.domain.is_none() {
+            return err(&[], "no SRS domain configured");
+        }
+        if self.secret_files.is_empty() {
+            return err(&[], "no secret file configured");
+        }
+        if self.listen.recv.is_none() && self.listen.send.is_none() && self.listen.socketmap.is_none() {
+            return err(&[], "no listen address configured");
+        }
+        if self.overflow_strategy == "database" && self.database_dir.is_none() {
+            return err(&["overflow_strategy"], "the database overflow strategy requires database_dir");
+        }
+        if self.compatibility == "libsrs2" && self.separator != "=" {
+            return err(&["compatibility", "separator"], "libsrs2 compatibility requires separator \"=\"");
+        }
+        if self.domain.as_ref().map_or(false, |d| d.contains(self.separator.as_str())) {
+            return err(&["domain", "separator"], "the SRS domain must not contain the separator");
+        }
+        if self.policy.srs_domains.iter().any(|&(_, ref srs)| srs.contains(self.separator.as_str())) {
+            return err(&["policy.srs_domains", "separator"], "SRS domains must not contain the separator");
+        }
+        if self.compatibility == "libsrs2" && self.timestamp != "days" {
+            return err(&["compatibility", "timestamp"], "libsrs2 compatibility requires timestamp \"days\"");
+        }
+        if self.hash_min_length.map_or(false, |m| m > self.hash_length) {
+            return err(&["hash_length", "hash_min_length"], "hash_min_length must not exceed hash_length");
+        }
+        if self.overflow_strategy == "compress" && self.hash_min_length.map_or(true, |m| m >= self.hash_length) {
+            return err(&["overflow_strategy", "hash_length", "hash_min_length"], "the compress overflow strategy requires a hash_min_length below hash_length");
+        }
+        return Ok(());
+    }
+
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::path::PathBuf;
+
+    use super::{Config,ConfigError,Value,parse_entries};
+
+    #[test]
+    fn it_parses_values() {
+        let e = parse_entries("a = \"x # y\" # comment\nb = 23\nc = true\n[s]\nd = [\"p\", \"q\\\"\" ]\ne = []").unwrap();
+        assert!(e == vec![
+            (1, "a".to_string(), Value::Str("x # y".to_string())),
+            (2, "b".to_string(), Value::Int(23)),
+            (3, "c".to_string(), Value::Bool(true)),
+            (5, "s.d".to_string(), Value::List(vec!["p".to_string(), "q\"".to_string()])),
+            (6, "s.e".to_string(), Value::List(vec![])),
+        ]);
+    }
+
+    #[test]
+    fn it_parses_a_full_config() {
+        let c = Config::from_str(r#"
+# rustsrsd.conf
+domain = "srs.example.com"
+separator = "+"
+scheme = "shortcut"
+strict_srs1 = true
+compatibility = "native"
+hash_algorithm = "sha256"
+fold_case = true
+smtputf8 = true
+hash_encoding = "base32"
+hash_length = 6
+hash_min_length = 4
+timestamp = "hours"
+max_age = 7
+max_future_skew = 2
+secret_files = ["/etc/rustsrsd/secret"]
+exclude_domains = ["example.com", ".example.org"]
+overflow_strategy = "database"
+database_dir = "/var/lib/rustsrsd"
+
+[listen]
+recv = "unix:/var/spool/postfix/private/srs-recv"
+send = "127.0.0.1:10002"
+socketmap = "unix:/var/spool/postfix/private/srs"
+
+[unix]
+owner = "postfix"
+mode = "0660"
+
+[policy]
+local_domains = ["example.net"]
+no_spf_domains = ["legacy.example"]
+skip_postmaster = false
+srs_domains = ["lists.example.net = srs.example.net", "b.example=srs-b.example"]
+"#).expect("config should be valid");
+        assert!(c.domain == Some("srs.example.com".to_string()));
+        assert!(c.separator == "+");
+        assert!(c.scheme == "shortcut");
+        assert!(c.strict_srs1);
+        assert!(c.hash_algorithm == "sha256");
+        assert!(c.fold_case);
+        assert!(c.smtputf8);
+        assert!(c.hash_encoding == "base32");
+        assert!(c.hash_length == 6);
+        assert!(c.hash_min_length == Some(4));
+        assert!(c.timestamp == "hours");
+        assert!(c.max_age == 7);
+        assert!(c.max_future_skew == 2);
+        assert!(c.secret_files == vec![PathBuf::from("/etc/rustsrsd/secret")]);
+        assert!(c.exclude_domains == vec!["example.com".to_string(), ".example.org".to_string()]);
+        assert!(c.policy.local_domains == vec!["example.net".to_string()]);
+        assert!(c.policy.no_spf_domains == vec!["legacy.example".to_string()]);
+        assert!(!c.policy.skip_postmaster);
+        assert!(c.policy.srs_domains == vec![("lists.example.net".to_string(), "srs.example.net".to_string()),
+                                             ("b.example".to_string(), "srs-b.example".to_string())]);
+        assert!(c.overflow_strategy == "database");
+        assert!(c.database_dir == Some(PathBuf::from("/var/lib/rustsrsd")));
+        assert!(c.listen.recv == Some("unix:/var/spool/postfix/private/srs-recv".to_string()));
+        assert!(c.listen.send == Some("127.0.0.1:10002".to_string()));
+        assert!(c.unix.owner == Some("postfix".to_string()));
+        assert!(c.unix.mode == Some(0o660));
+        assert!(c.validate().is_ok());
+    }
+
+    #[test]
+    fn it_reports_the_offending_line() {
+        fn line_of(text: &str) -> Option<usize> {
+            Config::from_str(text).unwrap_err().line
+        }
+        assert!(line_of("domain = \"a\"\n\nfoo = 1") == Some(3));
+        assert!(line_of("domain = \"a\"\nseparator = \"#\"") == Some(2));
+        assert!(line_of("hash_algorithm = \"md4\"") == Some(1));
+        assert!(line_of("max_age = 2000") == Some(1));
+        assert!(line_of("hash_length = 0") == Some(1));
+        assert!(line_of("hash_length = 1") == Some(1));
+        assert!(line_of("hash_min_length = 0") == Some(1));
+        assert!(line_of("hash_min_length = 1") == Some(1));
+        assert!(line_of("hash_length = 3") == Some(1));
+        assert!(line_of("hash_min_length = 3") == Some(1));
+        assert!(line_of("compatibility = \"postsrsd\"") == Some(1));
+        assert!(line_of("timestamp = \"minutes\"") == Some(1));
+        assert!(line_of("[policy]\nsrs_domains = [\"a.example\"]") == Some(2));
+        assert!(line_of("[policy]\nsrs_domains = [\"a.example = b_c\"]") == Some(2));
+        assert!(line_of("scheme = \"nested\"") == Some(1));
+        assert!(line_of("hash_encoding = \"hex\"") == Some(1));
+        assert!(line_of("overflow_strategy = \"truncate\"") == Some(1));
+        assert!(line_of("fold_case = 1") == Some(1));
+        assert!(line_of("hash_length = 25") == Some(1));
+        assert!(line_of("max_age = \"5\"") == Some(1));
+        assert!(line_of("domain = \"a") == Some(1));
+        assert!(line_of("[listen\nrecv = \"x\"") == Some(1));
+        assert!(line_of("exclude_domains = [\"a\",\n\"b\"]") == Some(1));
+        assert!(line_of("domain = \"a\"\ndomain = \"b\"") == Some(2));
+        assert!(line_of("domain = \"\"") == Some(1));
+        assert!(line_of("domain = \"srs example.com\"") == Some(1));
+        assert!(line_of("domain = \"-srs.example.com\"") == Some(1));
+        assert!(line_of("domain = \"srs.example.com.\"") == Some(1));
+        assert!(line_of("separator = \"-\"\ndomain = \"srs-a.example.com\"") == Some(2));
+        assert!(line_of("[listen]\nfoo = \"x\"") == Some(2));
+    }
+
+    #[test]
+    fn it_reports_the_line_of_conflicting_settings() {
+        fn line_of(c: &Config) -> Option<usize> {
+            c.validate().unwrap_err().line
+        }
+        let c = Config::from_str("secret_files = [\"s\"]").unwrap();
+        assert!(line_of(&c) == None);
+        let c = Config::from_str("domain = \"a\"\nsecret_files = [\"s\"]\ncompatibility = \"libsrs2\"\nseparator = \"+\"\n[listen]\nrecv = \"x\"").unwrap();
+        assert!(line_of(&c) == Some(4));
+        let c = Config::from_str("domain = \"a\"\nsecret_files = [\"s\"]\noverflow_strategy = \"compress\"\nhash_min_length = 4\n[listen]\nrecv = \"x\"").unwrap();
+        assert!(line_of(&c) == Some(4));
+        let c = Config::from_str("domain = \"a\"\nsecret_files = [\"s\"]\nseparator = \"-\"\n[listen]\nrecv = \"x\"\n[policy]\nsrs_domains = [\"b = srs-b\"]").unwrap();
+        assert!(line_of(&c) == Some(7));
+        // Command line overrides have no line, the conflict points to the file's side
+        let mut c = Config::from_str("domain = \"srs-a.example.com\"\nsecret_files = [\"s\"]\n[listen]\nrecv = \"x\"").unwrap();
+        c.set_from_cli("separator", "-").unwrap();
+        assert!(line_of(&c) == Some(1));
+        let mut c = Config::from_str("domain = \"a\"\nsecret_files = [\"s\"]\ncompatibility = \"libsrs2\"\nseparator = \"+\"\n[listen]\nrecv = \"x\"").unwrap();
+        c.set_from_cli("separator", "-").unwrap();
+        assert!(line_of(&c) == Some(3));
+    }
+
+    #[test]
+    fn it_lets_cli_override_the_file() {
+        let mut c = Config::from_str("max_age = 7\n[listen]\nrecv = \"127.0.0.1:1\"").unwrap();
+        c.set_from_cli("max_age", "3").unwrap();
+        c.set_from_cli("listen.recv", "unix:/tmp/recv").unwrap();
+        assert!(c.max_age == 3);
+        assert!(c.listen.recv == Some("unix:/tmp/recv".to_string()));
+        assert!(c.set_from_cli("max_age", "x") == Err(ConfigError{ line: None, message: "max_age: expected an integer, got \"x\"".to_string() }));
+    }
+
+    #[test]
+    fn it_requires_domain_secret_and_listener() {
+        assert!(Config::from_str("").unwrap().validate().is_err());
+        let c = Config::from_str("domain = \"a\"\nsecret_files = [\"s\"]").unwrap();
+        assert!(c.validate().is_err());
+        let c = Config::from_str("domain = \"a\"\nsecret_files = [\"s\"]\nhash_min_length = 5\n[listen]\nrecv = \"x\"").unwrap();
+        assert!(c.validate().is_err());
+        let c = Config::from_str("domain = \"a\"\nsecret_files = [\"s\"]\ncompatibility = \"libsrs2\"\nseparator = \"+\"\n[listen]\nrecv = \"x\"").unwrap();
+        assert!(c.validate().is_err());
+        let c = Config::from_str("domain = \"a\"\nsecret_files = [\"s\"]\ncompatibility = \"libsrs2\"\ntimestamp = \"none\"\n[listen]\nrecv = \"x\"").unwrap();
+        assert!(c.validate().is_err());
+        let c = Config::from_str("domain = \"a\"\nsecret_files = [\"s\"]\noverflow_strategy = \"database\"\n[listen]\nrecv = \"x\"").unwrap();
+        assert!(c.validate().is_err());
+        let c = Config::from_str("domain = \"a\"\nsecret_files = [\"s\"]\noverflow_strategy = \"compress\"\n[listen]\nrecv = \"x\"").unwrap();
+        assert!(c.validate().is_err());
+        let c = Config::from_str("domain = \"a\"\nsecret_files = [\"s\"]\noverflow_strategy = \"compress\"\nhash_min_length = 4\n[listen]\nrecv = \"x\"").unwrap();
+        assert!(c.validate().is_err());
+        let c = Config::from_str("domain = \"a\"\nsecret_files = [\"s\"]\noverflow_strategy = \"compress\"\nhash_length = 8\nhash_min_length = 4\n[listen]\nrecv = \"x\"").unwrap();
+        assert!(c.validate().is_ok());
+        let c = Config::from_str("domain = \"a\"\nsecret_files = [\"s\"]\nseparator = \"-\"\n[listen]\nrecv = \"x\"\n[policy]\nsrs_domains = [\"b = srs-b\"]").unwrap();
+        assert!(c.validate().is_err());
+    }
+
+}
@@ -0,0 +1,296 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::io::{Read,Write};
+use std::net::{TcpListener,TcpStream,Shutdown};
+use std::os::unix::fs::{FileTypeExt,PermissionsExt};
+use std::os::unix::net::{UnixListener,UnixStream};
+use std::path::{Path,PathBuf};
+use std::time::Duration;
+
+use libc;
+
+/// Where a protocol server listens: `unix:/path/to/socket` or a TCP `host:port`.
+#[derive(Clone,Debug,PartialEq)]
+pub enum ListenAddr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    pub fn parse(s: &str) -> ListenAddr {
+        if s.starts_with("unix:") {
+            return ListenAddr::Unix(PathBuf::from(&s[5..]));
+        }
+        if s.starts_with("inet:") {
+            return ListenAddr::Tcp(s[5..].to_string());
+        }
+        return ListenAddr::Tcp(s.to_string());
+    }
+}
+
+/// Ownership and mode applied to Unix socket files after binding.
+/// `None` leaves the respective attribute as created by the process, i.e. as the umask allows.
+#[derive(Clone,Debug,Default)]
+pub struct UnixSocketPermissions {
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    pub mode: Option<u32>,
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidInput, msg);
+}
+
+fn resolve_uid(owner: &str) -> io::Result<libc::uid_t> {
+    if let Ok(uid) = owner.parse() {
+        return Ok(uid);
+    }
+    let name = try!(CString::new(owner).map_err(|_| invalid_input(format!("invalid user name {:?}", owner))));
+    let pw = unsafe { libc::getpwnam(name.as_ptr()) };
+    if pw.is_null() {
+        return Err(invalid_input(format!("unknown user {:?}", owner)));
+    }
+    return Ok(unsafe { (*pw).pw_uid });
+}
+
+fn resolve_gid(group: &str) -> io::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = try!(CString::new(group).map_err(|_| invalid_input(format!("invalid group name {:?}", group))));
+    let gr = unsafe { libc::getgrnam(name.as_ptr()) };
+    if gr.is_null() {
+        return Err(invalid_input(format!("unknown group {:?}", group)));
+    }
+    return Ok(unsafe { (*gr).gr_gid });
+}
+
+impl UnixSocketPermissions {
+
+    /// `default_mode` is used if no mode is configured
+    fn apply(&self, path: &Path, default_mode: u32) -> io::Result<()> {
+
+        if self.owner.is_some() || self.group.is_some() {
+            // -1 leaves the respective id unchanged, see chown(2)
+            let uid = match self.owner {
+                Some(ref o) => try!(resolve_uid(o)),
+                None        => !0,
+            };
+            let gid = match self.group {
+                Some(ref g) => try!(resolve_gid(g)),
+                None        => !0,
+            };
+            let cpath = try!(CString::new(path.to_string_lossy().into_owned())
+                             .map_err(|_| invalid_input(format!("invalid socket path {:?}", path))));
+            if unsafe { libc::chown(cpath.as_ptr(), uid, gid) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        let mode = self.mode.unwrap_or(default_mode);
+        return fs::set_permissions(path, fs::Permissions::from_mode(mode));
+    }
+
+}
+
+/// Bind with access for our user only, so nobody connects before `UnixSocketPermissions`
+/// are applied. Also returns the mode the process' umask would have given the socket.
+fn bind_private(path: &Path) -> io::Result<(UnixListener, u32)> {
+    // The umask is process-wide, listeners are only bound at startup
+    let umask = unsafe { libc::umask(0o177) };
+    let res = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    return res.map(|l| (l, 0o777 & !(umask as u32)));
+}
+
+/// Remove a socket file left behind by a previous instance.
+/// Refuses to remove anything that is not a socket or that still accepts connections.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+
+    let meta = match fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    if !meta.file_type().is_socket() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                  format!("{:?} exists and is not a socket", path)));
+    }
+
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                  format!("{:?} is in use by another process", path)));
+    }
+
+    println!("removing stale socket {:?}", path);
+    return fs::remove_file(path);
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+pub enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Listener {
+
+    pub fn bind(addr: &ListenAddr, perms: &UnixSocketPermissions) -> io::Result<Listener> {
+        match *addr {
+            ListenAddr::Tcp(ref a) => {
+                let l = try!(TcpListener::bind(a.as_str()));
+                return Ok(Listener::Tcp(l));
+            },
+            ListenAddr::Unix(ref path) => {
+                try!(remove_stale_socket(path));
+                let (l, default_mode) = try!(bind_private(path));
+                if let Err(e) = perms.apply(path, default_mode) {
+                    let _ = fs::remove_file(path);
+                    return Err(e);
+                }
+                return Ok(Listener::Unix(l, path.clone()));
+            },
+        }
+    }
+
+    /// The socket file to remove on shutdown, if any.
+    pub fn socket_path(&self) -> Option<PathBuf> {
+        match *self {
+            Listener::Tcp(_)            => None,
+            Listener::Unix(_, ref path) => Some(path.clone()),
+        }
+    }
+
+    /// Accept a connection, returning it together with a printable peer description.
+    pub fn accept(&self) -> io::Result<(Connection, String)> {
+        match *self {
+            Listener::Tcp(ref l) => {
+                let (s, peer) = try!(l.accept());
+                return Ok((Connection::Tcp(s), format!("{}", peer)));
+            },
+            Listener::Unix(ref l, ref path) => {
+                let (s, _) = try!(l.accept());
+                return Ok((Connection::Unix(s), format!("unix:{}", path.display())));
+            },
+        }
+    }
+
+}
+
+impl Connection {
+
+    pub fn try_clone(&self) -> io::Result<Connection> {
+        match *self {
+            Connection::Tcp(ref s)  => s.try_clone().map(Connection::Tcp),
+            Connection::Unix(ref s) => s.try_clone().map(Connection::Unix),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match *self {
+            Connection::Tcp(ref s)  => s.set_read_timeout(timeout),
+            Connection::Unix(ref s) => s.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn shutdown(&self) -> io::Result<()> {
+        match *self {
+            Connection::Tcp(ref s)  => s.shutdown(Shutdown::Both),
+            Connection::Unix(ref s) => s.shutdown(Shutdown::Both),
+        }
+    }
+
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Tcp(ref mut s)  => s.read(buf),
+            Connection::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Tcp(ref mut s)  => s.write(buf),
+            Connection::Unix(ref mut s) => s.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Connection::Tcp(ref mut s)  => s.flush(),
+            Connection::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::process;
+
+    use super::{ListenAddr,Listener,UnixSocketPermissions};
+
+    fn temp_socket_path(name: &str) -> PathBuf {
+        let mut p = env::temp_dir();
+        p.push(format!("rustsrsd-test-{}-{}.sock", process::id(), name));
+        let _ = fs::remove_file(&p);
+        return p;
+    }
+
+    #[test]
+    fn it_parses_listen_addrs() {
+        assert!(ListenAddr::parse("unix:/var/spool/postfix/private/srs")
+                == ListenAddr::Unix(PathBuf::from("/var/spool/postfix/private/srs")));
+        assert!(ListenAddr::parse("inet:127.0.0.1:10001") == ListenAddr::Tcp("127.0.0.1:10001".to_string()));
+        assert!(ListenAddr::parse("127.0.0.1:10001") == ListenAddr::Tcp("127.0.0.1:10001".to_string()));
+    }
+
+    #[test]
+    fn it_applies_socket_mode() {
+        let path = temp_socket_path("mode");
+        let perms = UnixSocketPermissions{ mode: Some(0o660), ..Default::default() };
+        let l = Listener::bind(&ListenAddr::Unix(path.clone()), &perms).expect("should bind");
+        let mode = fs::metadata(&path).expect("socket should exist").permissions().mode();
+        assert!(mode & 0o777 == 0o660);
+        drop(l);
+        fs::remove_file(&path).expect("socket should be removable");
+    }
+
+    #[test]
+    fn it_replaces_stale_sockets_but_not_live_ones() {
+        let path = temp_socket_path("stale");
+        let addr = ListenAddr::Unix(path.clone());
+        let perms = UnixSocketPermissions::default();
+
+        let live = Listener::bind(&addr, &perms).expect("should bind");
+        assert!(Listener::bind(&addr, &perms).is_err());
+
+        drop(live); // leaves the socket file behind
+        assert!(path.exists());
+        let l = Listener::bind(&addr, &perms).expect("should replace stale socket");
+        assert!(l.socket_path() == Some(path.clone()));
+        fs::remove_file(&path).expect("socket should be removable");
+    }
+
+    #[test]
+    fn it_does_not_remove_regular_files() {
+        let path = temp_socket_path("regular");
+        fs::write(&path, "not a socket").expect("should create file");
+        assert!(Listener::bind(&ListenAddr::Unix(path.clone()), &UnixSocketPermissions::default()).is_err());
+        assert!(path.exists());
+        fs::remove_file(&path).expect("file should be removable");
+    }
+
+}
@@ -0,0 +1,377 @@
+use srs::error::SRSError;
+use srs::parser::{SRSAddressRef,Charset};
+use srs::transformers::{Forwarder,Forwarded,Receiver,Timestamper};
+use srs::database::{self,DatabaseForwarder,DatabaseReceiver,FileStore};
+use srs::envelope;
+use srs::policy::{Policy,Decision};
+
+/// The direction a lookup table rewrites addresses in.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Table {
+    /// Envelope sender => SRS address, backed by `Forwarder::forward`
+    Forward,
+    /// SRS address => previous hop, backed by `Receiver::receive`
+    Reverse,
+}
+
+/// Protocol-independent outcome of a lookup.
+/// The protocol servers map these to their reply codes.
+#[derive(Debug,PartialEq)]
+pub enum LookupResult {
+    Found(String),
+    NotFound(String),
+    TempFail(String),
+    PermFail(String),
+}
+
+pub struct Lookup<T> where T: Timestamper {
+    pub forwarder: Forwarder<T>,
+    pub receiver: Receiver<T>,
+    /// Decides which senders are rewritten and under which SRS domain
+    pub policy: Policy,
+    /// Stores senders too long for the forwarder, see `OverflowStrategy::Database`
+    pub database_forwarder: Option<DatabaseForwarder<FileStore,T>>,
+    pub database_receiver: Option<DatabaseReceiver<FileStore,T>>,
+    /// Addresses with other characters are not rewritten
+    pub charset: Charset,
+}
+
+/// Replies carry the error's stable code, e.g. `PERM hash-mismatch: hash verification failed`
+fn failure(e: SRSError) -> LookupResult {
+    let reason = format!("{}: {}", e.code(), e);
+    match e.is_temporary() {
+        true  => LookupResult::TempFail(reason),
+        false => LookupResult::PermFail(reason),
+    }
+}
+
+impl<T> Lookup<T> where T: Timestamper {
+
+    pub fn new(forwarder: Forwarder<T>, receiver: Receiver<T>) -> Lookup<T> {
+        return Lookup{
+            forwarder: forwarder,
+            receiver: receiver,
+            policy: Policy::new(),
+            database_forwarder: None,
+            database_receiver: None,
+            charset: Charset::ASCII,
+        };
+    }
+
+    /// `recipient_domain` is only known to some clients, see `socketmap`
+    pub fn lookup_for(&self, table: Table, key: &str, recipient_domain: Option<&str>) -> LookupResult {
+        match table {
+            Table::Forward => self.forward(key, recipient_domain),
+            Table::Reverse => self.reverse(key),
+        }
+    }
+
+    /// Forward lookup for mail to `recipient_domain`, `None` if the client did not tell it.
+    /// Rewrites under the SRS domain the policy picks for it.
+    pub fn forward(&self, key: &str, recipient_domain: Option<&str>) -> LookupResult {
+        let (res, rule) = self.forward_with_rule(key, recipient_domain);
+        if let (&LookupResult::Found(ref rewritten), Some(rule)) = (&res, rule) {
+            println!("{:?} rewritten to {:?} by rule {}", key, rewritten, rule);
+        }
+        return res;
+    }
+
+    /// Like `forward`, but also returns the policy rule that decided, `None` if the
+    /// sender did not parse. Skipped senders also carry it in the `NotFound` text.
+    pub fn forward_with_rule(&self, key: &str, recipient_domain: Option<&str>) -> (LookupResult, Option<&str>) {
+
+        // Senders we cannot parse are left alone
+        let address = match envelope::parse_envelope_sender(key, self.charset) {
+            Ok(a)  => a,
+            Err(e) => return (LookupResult::NotFound(format!("{}: {}", e.code(), e)), None),
+        };
+
+        let (srs_domain, rule) = match self.policy.decide(&address, recipient_domain) {
+            Decision::Skip{ rule }                => return (LookupResult::NotFound(format!("skipped by rule {}", rule)), Some(rule)),
+            Decision::Rewrite{ srs_domain, rule } => (srs_domain, rule),
+        };
+
+        // Stored without brackets or source route, the reverse lookup returns it as is
+        let sender = match self.database_forwarder {
+            Some(_) => address.to_string(),
+            None    => String::new(),
+        };
+
+        let forwarded = match srs_domain {
+            Some(d) => self.forwarder.forward_checked_as(address, d),
+            None    => self.forwarder.forward_checked(address),
+        };
+        let stored = |db: &DatabaseForwarder<FileStore,T>| match srs_domain {
+            Some(d) => db.forward_as(&sender, d),
+            None    => db.forward(&sender),
+        };
+        let res = match forwarded {
+            Err(SRSError::InvalidSender) => LookupResult::NotFound("invalid-sender: not a valid RFC 5321 address".to_string()),
+            Err(e @ SRSError::QuotedLocalPart) => LookupResult::NotFound(format!("{}: {}", e.code(), e)),
+            Ok(Forwarded::Fits(srs)) => LookupResult::Found(srs.to_string()),
+            Ok(Forwarded::Compressed(srs)) => {
+                println!("{:?} compressed to fit the local part limit", key);
+                LookupResult::Found(srs.to_string())
+            },
+            Ok(Forwarded::Database(len)) => match self.database_forwarder {
+                Some(ref db) => match stored(db) {
+                    Ok(a) => {
+                        println!("{:?} stored in database, local part would be {} octets", key, len);
+                        LookupResult::Found(a)
+                    },
+                    Err(e) => failure(e),
+                },
+                None => failure(SRSError::LocalPartTooLong(len)),
+            },
+            Err(e) => failure(e),
+        };
+        return (res, Some(rule));
+    }
+
+    fn reverse(&self, key: &str) -> LookupResult {
+
+        if let Some(ref db) = self.database_receiver {
+            if database::parse_id(key).is_some() {
+                return match db.receive(key) {
+                    Ok(sender) => LookupResult::Found(sender),
+                    Err(e)     => failure(e),
+                };
+            }
+        }
+
+        let address = match SRSAddressRef::parse(key, self.charset) {
+            Ok(a)                 => a,
+            Err(SRSError::NotSRS) => return LookupResult::NotFound("not an SRS address".to_string()),
+            Err(e)                => return failure(e),
+        };
+
+        return match self.receiver.receive_ref_with_key_index(&address) {
+            Ok((rewritten, key_index)) => {
+                if key_index > 0 {
+                    println!("{:?} verified with secret #{}", key, key_index);
+                }
+                LookupResult::Found(rewritten)
+            },
+            Err(e) => failure(e),
+        };
+    }
+
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use openssl::hash::MessageDigest;
+
+    use srs::database::{DatabaseForwarder,DatabaseReceiver,FileStore};
+    use srs::parser::Charset;
+    use srs::policy::{Rule,Matcher,Action,DEFAULT_RULE};
+    use srs::transformers::{Forwarder,Receiver,SRSTimestamper,OverflowStrategy};
+    use super::{Lookup,LookupResult,Table};
+
+    fn make_lookup(key: &str, hostname: &str) -> Lookup<SRSTimestamper> {
+        let f = Forwarder::new(key.to_owned().into_bytes(), hostname.to_owned().into_bytes(),
+                               MessageDigest::sha512(), "=", SRSTimestamper::new(5))
+            .expect("test should supply valid forwarder params");
+        let r = Receiver::new(key.to_owned().into_bytes(), hostname.to_owned().into_bytes(),
+                              MessageDigest::sha512(), SRSTimestamper::new(5))
+            .expect("test should supply valid receiver params");
+        return Lookup::new(f, r);
+    }
+
+    #[test]
+    fn it_reverses_its_own_forward() {
+        let l = make_lookup("asecret", "a");
+        let srs = match l.lookup_for(Table::Forward, "user@origin", None) {
+            LookupResult::Found(s) => s,
+            x => panic!("unexpected lookup result {:?}", x),
+        };
+        assert!(srs.starts_with("SRS0="));
+        assert!(srs.ends_with("=origin=user@a"));
+        assert!(l.lookup_for(Table::Reverse, &srs, None) == LookupResult::Found("user@origin".to_string()));
+    }
+
+    #[test]
+    fn it_does_not_find_plain_addresses_in_reverse_table() {
+        let l = make_lookup("asecret", "a");
+        match l.lookup_for(Table::Reverse, "user@origin", None) {
+            LookupResult::NotFound(_) => (),
+            x => panic!("unexpected lookup result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn it_fails_permanently_on_forged_hash() {
+        let l = make_lookup("asecret", "a");
+        match l.lookup_for(Table::Reverse, "SRS0=HHHH=TT=origin=user@a", None) {
+            LookupResult::PermFail(ref r) if r.starts_with("hash-mismatch: ") => (),
+            x => panic!("unexpected lookup result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn it_does_not_forward_excluded_domains() {
+        let mut l = make_lookup("asecret", "a");
+        l.policy.rules.push(Rule{
+            name: "local_domains".to_string(),
+            matcher: Matcher::SenderDomain(vec!["example.com".to_string(), ".example.org".to_string()]),
+            action: Action::Skip,
+        });
+        for excluded in vec!["user@example.com", "user@EXAMPLE.com", "user@mail.example.org"] {
+            match l.lookup_for(Table::Forward, excluded, None) {
+                LookupResult::NotFound(ref r) if r == "skipped by rule local_domains" => (),
+                x => panic!("unexpected lookup result {:?}", x),
+            }
+        }
+        match l.lookup_for(Table::Forward, "<POSTMASTER@origin>", None) {
+            LookupResult::NotFound(ref r) if r == "skipped by rule postmaster" => (),
+            x => panic!("unexpected lookup result {:?}", x),
+        }
+        for rewritten in vec!["user@mail.example.com", "user@example.org"] {
+            match l.lookup_for(Table::Forward, rewritten, None) {
+                LookupResult::Found(_) => (),
+                x => panic!("unexpected lookup result {:?}", x),
+            }
+        }
+    }
+
+    #[test]
+    fn it_picks_the_srs_domain_by_recipient() {
+        let mut l = make_lookup("asecret", "a");
+        l.policy.rules.push(Rule{
+            name: "srs_domains".to_string(),
+            matcher: Matcher::RecipientDomain(vec!["lists.example.net".to_string()]),
+            action: Action::RewriteAs("srs.example.net".to_string()),
+        });
+        let srs = match l.lookup_for(Table::Forward, "user@origin", Some("lists.example.net")) {
+            LookupResult::Found(s) => s,
+            x => panic!("unexpected lookup result {:?}", x),
+        };
+        assert!(srs.ends_with("=origin=user@srs.example.net"));
+        assert!(l.lookup_for(Table::Reverse, &srs, None) == LookupResult::Found("user@origin".to_string()));
+        match l.lookup_for(Table::Forward, "user@origin", Some("example.net")) {
+            LookupResult::Found(ref s) if s.ends_with("=origin=user@a") => (),
+            x => panic!("unexpected lookup result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn it_reports_the_rule_that_decided() {
+        let mut l = make_lookup("asecret", "a");
+        l.policy.rules.push(Rule{
+            name: "local_domains".to_string(),
+            matcher: Matcher::SenderDomain(vec!["example.com".to_string()]),
+            action: Action::Skip,
+        });
+        l.policy.rules.push(Rule{
+            name: "srs_domains".to_string(),
+            matcher: Matcher::RecipientDomain(vec!["lists.example.net".to_string()]),
+            action: Action::RewriteAs("srs.example.net".to_string()),
+        });
+        match l.forward_with_rule("user@example.com", None) {
+            (LookupResult::NotFound(_), Some("local_domains")) => (),
+            x => panic!("unexpected lookup result {:?}", x),
+        }
+        match l.forward_with_rule("user@origin", Some("lists.example.net")) {
+            (LookupResult::Found(ref s), Some("srs_domains")) if s.ends_with("@srs.example.net") => (),
+            x => panic!("unexpected lookup result {:?}", x),
+        }
+        match l.forward_with_rule("user@origin", None) {
+            (LookupResult::Found(_), Some(DEFAULT_RULE)) => (),
+            x => panic!("unexpected lookup result {:?}", x),
+        }
+        match l.forward_with_rule("user", None) {
+            (LookupResult::NotFound(_), None) => (),
+            x => panic!("unexpected lookup result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn it_does_not_forward_addresses_without_domain() {
+        let l = make_lookup("asecret", "a");
+        for key in vec!["user", "<>", "<user@origin"] {
+            match l.lookup_for(Table::Forward, key, None) {
+                LookupResult::NotFound(_) => (),
+                x => panic!("unexpected lookup result {:?}", x),
+            }
+        }
+        match l.lookup_for(Table::Forward, "us..er@origin", None) {
+            LookupResult::NotFound(ref r) if r.starts_with("invalid-sender: ") => (),
+            x => panic!("unexpected lookup result {:?}", x),
+        }
+        match l.lookup_for(Table::Forward, "<\"john doe\"@origin>", None) {
+            LookupResult::NotFound(ref r) if r.starts_with("quoted-local-part: ") => (),
+            x => panic!("unexpected lookup result {:?}", x),
+        }
+        match l.lookup_for(Table::Forward, "<user@origin>", None) {
+            LookupResult::Found(ref s) if s.ends_with("=origin=user@a") => (),
+            x => panic!("unexpected lookup result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn it_forwards_utf8_senders_only_with_smtputf8() {
+        let mut l = make_lookup("asecret", "a");
+        match l.lookup_for(Table::Forward, "j\u{f6}rg@b\u{fc}cher.example", None) {
+            LookupResult::NotFound(ref r) if r.starts_with("malformed: ") => (),
+            x => panic!("unexpected lookup result {:?}", x),
+        }
+        l.charset = Charset::SMTPUTF8;
+        l.forwarder.charset = Charset::SMTPUTF8;
+        let srs = match l.lookup_for(Table::Forward, "j\u{f6}rg@b\u{fc}cher.example", None) {
+            LookupResult::Found(s) => s,
+            x => panic!("unexpected lookup result {:?}", x),
+        };
+        assert!(srs.ends_with("=b\u{fc}cher.example=j\u{f6}rg@a"));
+        assert!(l.lookup_for(Table::Reverse, &srs, None) == LookupResult::Found("j\u{f6}rg@b\u{fc}cher.example".to_string()));
+    }
+
+    #[test]
+    fn it_stores_overlong_senders_in_the_database() {
+        let long = format!("{}@origin", "x".repeat(60));
+
+        let mut l = make_lookup("asecret", "a");
+        match l.lookup_for(Table::Forward, &long, None) {
+            LookupResult::PermFail(_) => (),
+            x => panic!("unexpected lookup result {:?}", x),
+        }
+
+        let mut dir = env::temp_dir();
+        dir.push(format!("rustsrsd-test-{}-lookup.db", process::id()));
+        let store = FileStore::new(dir.clone()).expect("should create store");
+        l.forwarder.overflow_strategy = OverflowStrategy::Database;
+        l.database_forwarder = Some(DatabaseForwarder::new(store.clone(), "a".to_string(), "=",
+                                                           SRSTimestamper::new(5)).unwrap());
+        l.database_receiver = Some(DatabaseReceiver::new(store, SRSTimestamper::new(5)));
+
+        let srs = match l.lookup_for(Table::Forward, &format!("<{}>", long), None) {
+            LookupResult::Found(s) => s,
+            x => panic!("unexpected lookup result {:?}", x),
+        };
+        assert!(srs.len() < 64);
+        assert!(l.lookup_for(Table::Reverse, &srs, None) == LookupResult::Found(long.clone()));
+        // short senders still use the stateless scheme
+        match l.lookup_for(Table::Forward, "user@origin", None) {
+            LookupResult::Found(ref s) if s.ends_with("=origin=user@a") => (),
+            x => panic!("unexpected lookup result {:?}", x),
+        }
+        // under the SRS domain the policy picked
+        l.policy.rules.push(Rule{
+            name: "srs_domains".to_string(),
+            matcher: Matcher::RecipientDomain(vec!["lists.example.net".to_string()]),
+            action: Action::RewriteAs("srs.example.net".to_string()),
+        });
+        let srs = match l.lookup_for(Table::Forward, &long, Some("lists.example.net")) {
+            LookupResult::Found(s) => s,
+            x => panic!("unexpected lookup result {:?}", x),
+        };
+        assert!(srs.ends_with("@srs.example.net"));
+        assert!(l.lookup_for(Table::Reverse, &srs, None) == LookupResult::Found(long.clone()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
@@ -0,0 +1,13 @@
+pub mod lookup;
+
+pub mod tcp_table;
+
+pub mod socketmap;
+
+pub mod listener;
+
+pub mod signals;
+
+pub mod server;
+
+pub mod config;
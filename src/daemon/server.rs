@@ -0,0 +1,231 @@
+use std::io;
+use std::io::BufReader;
+use std::sync::{Arc,RwLock};
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::thread;
+use std::time::Duration;
+
+use daemon::{tcp_table,socketmap};
+use daemon::listener::{Listener,Connection};
+use daemon::lookup::{Lookup,Table};
+use srs::transformers::Timestamper;
+
+/// The lookup currently in effect. Replacing the inner `Arc` (e.g. after
+/// reloading secrets) affects all subsequent requests, including those on
+/// connections that are already open.
+pub type SharedLookup<T> = Arc<RwLock<Arc<Lookup<T>>>>;
+
+pub fn current_lookup<T>(shared: &SharedLookup<T>) -> Arc<Lookup<T>> where T: Timestamper {
+    return shared.read().expect("lookup lock poisoned").clone();
+}
+
+#[derive(Clone,Copy,Debug)]
+pub enum Protocol {
+    TcpTable(Table),
+    Socketmap,
+}
+
+#[derive(Clone,Copy,Debug)]
+pub struct ServerConfig {
+    pub max_request_len: usize,
+    /// Connections idle for longer than this are closed, `None` waits forever
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Caps the number of connections served concurrently, shared by all listeners.
+pub struct ConnectionLimit {
+    active: AtomicUsize,
+    max: usize,
+}
+
+/// Holds one slot of a `ConnectionLimit` until dropped.
+pub struct ConnectionSlot {
+    limit: Arc<ConnectionLimit>,
+}
+
+impl ConnectionLimit {
+
+    pub fn new(max: usize) -> ConnectionLimit {
+        return ConnectionLimit{
+            active: AtomicUsize::new(0),
+            max: max,
+        };
+    }
+
+    pub fn acquire(limit: &Arc<ConnectionLimit>) -> Option<ConnectionSlot> {
+        let prev = limit.active.fetch_add(1, Ordering::SeqCst);
+        if prev >= limit.max {
+            limit.active.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        return Some(ConnectionSlot{ limit: limit.clone() });
+    }
+
+    pub fn active(&self) -> usize {
+        return self.active.load(Ordering::SeqCst);
+    }
+
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.limit.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn serve_connection<T>(stream: Connection, protocol: Protocol, lookup: &SharedLookup<T>, config: ServerConfig) -> io::Result<()>
+    where T: Timestamper {
+
+    try!(stream.set_read_timeout(config.idle_timeout));
+
+    let mut reader = BufReader::new(try!(stream.try_clone()));
+    let mut writer = stream;
+
+    let lookup = |table, key: &str, recipient_domain: Option<&str>| {
+        let res = current_lookup(lookup).lookup_for(table, key, recipient_domain);
+        match recipient_domain {
+            Some(d) => println!("{:?} lookup {:?} for {:?}: {:?}", table, key, d, res),
+            None    => println!("{:?} lookup {:?}: {:?}", table, key, res),
+        }
+        res
+    };
+    let res = match protocol {
+        Protocol::TcpTable(table) => tcp_table::serve(&mut reader, &mut writer, config.max_request_len, |key| lookup(table, key, None)),
+        Protocol::Socketmap       => socketmap::serve(&mut reader, &mut writer, config.max_request_len, lookup),
+    };
+
+    let _ = writer.shutdown();
+    return res;
+}
+
+/// Accept connections on `listener` forever, serving each one on its own thread.
+/// Connections beyond `limit` are closed right away.
+pub fn run<T>(listener: Listener, protocol: Protocol, lookup: SharedLookup<T>, limit: Arc<ConnectionLimit>, config: ServerConfig)
+    where T: Timestamper + Send + Sync + 'static {
+
+    loop {
+        let (stream, peer) = match listener.accept() {
+            Ok(x)  => x,
+            Err(e) => {
+                println!("Error accepting connection: {:?}", e);
+                continue;
+            },
+        };
+
+        let slot = match ConnectionLimit::acquire(&limit) {
+            Some(s) => s,
+            None    => {
+                println!("Rejecting connection from {}: connection limit reached ({} active)", peer, limit.active());
+                let _ = stream.shutdown();
+                continue;
+            },
+        };
+
+        println!("Connection from {}", peer);
+        let lookup = lookup.clone();
+        thread::spawn(move || {
+            let _slot = slot;
+            match serve_connection(stream, protocol, &lookup, config) {
+                Ok(()) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                    println!("Closing idle connection from {}", peer);
+                },
+                Err(e) => println!("error serving connection from {}: {:?}", peer, e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::io::{BufRead,BufReader,Read,Write};
+    use std::net::TcpStream;
+    use std::sync::{Arc,RwLock};
+    use std::thread;
+    use std::time::Duration;
+
+    use openssl::hash::MessageDigest;
+
+    use daemon::listener::{ListenAddr,Listener,UnixSocketPermissions};
+    use daemon::lookup::{Lookup,Table};
+    use srs::transformers::{Forwarder,Receiver,SRSTimestamper};
+    use super::{run,ConnectionLimit,Protocol,ServerConfig};
+
+    fn start_server(max_connections: usize, idle_timeout: Option<Duration>) -> String {
+        let f = Forwarder::new("asecret".to_owned().into_bytes(), "a".to_owned().into_bytes(),
+                               MessageDigest::sha512(), "=", SRSTimestamper::new(5))
+            .expect("test should supply valid forwarder params");
+        let r = Receiver::new("asecret".to_owned().into_bytes(), "a".to_owned().into_bytes(),
+                              MessageDigest::sha512(), SRSTimestamper::new(5))
+            .expect("test should supply valid receiver params");
+        let lookup = Arc::new(RwLock::new(Arc::new(Lookup::new(f, r))));
+
+        let listener = Listener::bind(&ListenAddr::Tcp("127.0.0.1:0".to_string()), &UnixSocketPermissions::default())
+            .expect("should bind to ephemeral port");
+        let addr = match listener {
+            Listener::Tcp(ref l) => l.local_addr().expect("should have local addr").to_string(),
+            _ => unreachable!(),
+        };
+        let limit = Arc::new(ConnectionLimit::new(max_connections));
+        let config = ServerConfig{ max_request_len: 1000, idle_timeout: idle_timeout };
+        thread::spawn(move || run(listener, Protocol::TcpTable(Table::Forward), lookup, limit, config));
+        return addr;
+    }
+
+    fn get(conn: &mut BufReader<TcpStream>, key: &str) -> String {
+        conn.get_mut().write_all(format!("get {}\n", key).as_bytes()).expect("should write request");
+        let mut line = String::new();
+        conn.read_line(&mut line).expect("should read reply");
+        return line;
+    }
+
+    #[test]
+    fn it_limits_connection_slots() {
+        let limit = Arc::new(ConnectionLimit::new(2));
+        let a = ConnectionLimit::acquire(&limit).expect("first slot is free");
+        let b = ConnectionLimit::acquire(&limit).expect("second slot is free");
+        assert!(ConnectionLimit::acquire(&limit).is_none());
+        drop(a);
+        assert!(limit.active() == 1);
+        let c = ConnectionLimit::acquire(&limit);
+        assert!(c.is_some());
+        drop(b);
+        drop(c);
+        assert!(limit.active() == 0);
+    }
+
+    #[test]
+    fn it_serves_connections_concurrently() {
+        let addr = start_server(10, None);
+        let mut first = BufReader::new(TcpStream::connect(addr.as_str()).expect("should connect"));
+        let mut second = BufReader::new(TcpStream::connect(addr.as_str()).expect("should connect"));
+        // the first connection stays open while the second is served
+        assert!(get(&mut second, "user@origin").starts_with("200 SRS0="));
+        assert!(get(&mut first, "user@origin").starts_with("200 SRS0="));
+        assert!(get(&mut second, "user@origin").starts_with("200 SRS0="));
+    }
+
+    #[test]
+    fn it_closes_connections_beyond_the_limit() {
+        let addr = start_server(1, None);
+        let mut first = BufReader::new(TcpStream::connect(addr.as_str()).expect("should connect"));
+        assert!(get(&mut first, "user@origin").starts_with("200 SRS0="));
+        let mut second = TcpStream::connect(addr.as_str()).expect("should connect");
+        let mut buf = Vec::new();
+        second.read_to_end(&mut buf).expect("should be closed by server");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn it_closes_idle_connections() {
+        let addr = start_server(10, Some(Duration::from_millis(50)));
+        let mut conn = BufReader::new(TcpStream::connect(addr.as_str()).expect("should connect"));
+        assert!(get(&mut conn, "user@origin").starts_with("200 SRS0="));
+        thread::sleep(Duration::from_millis(200));
+        let mut buf = Vec::new();
+        conn.read_to_end(&mut buf).expect("should be closed by server");
+        assert!(buf.is_empty());
+    }
+
+}
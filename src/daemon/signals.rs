@@ -0,0 +1,38 @@
+use std::sync::atomic::{AtomicBool,Ordering};
+
+use libc;
+
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_shutdown(_signum: libc::c_int) {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Record SIGINT and SIGTERM instead of terminating, see `shutdown_requested`.
+pub fn install_shutdown_handler() {
+    unsafe {
+        libc::signal(libc::SIGINT, handle_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handle_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+pub fn shutdown_requested() -> bool {
+    return SHUTDOWN_REQUESTED.load(Ordering::SeqCst);
+}
+
+extern "C" fn handle_reload(_signum: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Record SIGHUP instead of terminating, see `take_reload_request`.
+pub fn install_reload_handler() {
+    unsafe {
+        libc::signal(libc::SIGHUP, handle_reload as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+/// Returns true once for every batch of SIGHUPs received since the last call.
+pub fn take_reload_request() -> bool {
+    return RELOAD_REQUESTED.swap(false, Ordering::SeqCst);
+}
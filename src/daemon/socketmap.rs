@@ -0,0 +1,200 @@
+/* Postfix socketmap_table(5) lookup protocol
+ *
+ * Requests and replies are netstrings (<len>:<data>,), a connection carries any number of them:
+ *
+ *  name SPACE key  => OK SPACE data         (found)
+ *                     NOTFOUND SPACE        (not found)
+ *                     TEMP SPACE reason     (temporary error)
+ *                     PERM SPACE reason     (permanent error)
+ *
+ * The map name selects the table: "forward" rewrites senders, "reverse" reverses SRS addresses.
+ * "forward@example.net" rewrites senders of mail to example.net, for per-recipient SRS domains.
+ */
+
+use std::io;
+use std::io::{BufRead,Read,Write};
+
+use daemon::lookup::{LookupResult,Table};
+
+#[derive(Debug,PartialEq)]
+pub enum NetstringErr {
+    InvalidLength,
+    TooLong(usize),
+    MissingTerminator,
+    UnexpectedEof,
+}
+
+fn write_netstring<W: Write>(writer: &mut W, data: &str) -> io::Result<()> {
+    try!(write!(writer, "{}:{},", data.len(), data));
+    return writer.flush();
+}
+
+/// Read one netstring. Returns `Ok(None)` if the client closed the connection between requests.
+pub fn read_netstring<R: BufRead>(reader: &mut R, max_len: usize) -> io::Result<Result<Option<Vec<u8>>, NetstringErr>> {
+
+    let mut len_buf = Vec::new();
+    // usize::MAX has 20 digits, anything longer is garbage
+    try!((&mut *reader).take(21).read_until(b':', &mut len_buf));
+    if len_buf.is_empty() {
+        return Ok(Ok(None));
+    }
+    if len_buf.pop() != Some(b':') || len_buf.is_empty() || !len_buf.iter().all(|b| b.is_ascii_digit()) {
+        return Ok(Err(NetstringErr::InvalidLength));
+    }
+    let len: usize = match String::from_utf8_lossy(&len_buf).parse() {
+        Ok(l)  => l,
+        Err(_) => return Ok(Err(NetstringErr::InvalidLength)),
+    };
+    if len > max_len {
+        return Ok(Err(NetstringErr::TooLong(len)));
+    }
+
+    let mut data = vec![0; len + 1];
+    match reader.read_exact(&mut data) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(Err(NetstringErr::UnexpectedEof)),
+        Err(e) => return Err(e),
+    }
+    if data.pop() != Some(b',') {
+        return Ok(Err(NetstringErr::MissingTerminator));
+    }
+
+    return Ok(Ok(Some(data)));
+}
+
+/// The table and the recipient domain, if the map name carries one
+fn map_table(name: &str) -> Option<(Table, Option<&str>)> {
+    match name {
+        "forward" => Some((Table::Forward, None)),
+        "reverse" => Some((Table::Reverse, None)),
+        n if n.starts_with("forward@") && n.len() > 8 => Some((Table::Forward, Some(&n[8..]))),
+        _         => None,
+    }
+}
+
+fn handle_request<F>(request: &[u8], lookup: &F) -> LookupResult
+    where F: Fn(Table, &str, Option<&str>) -> LookupResult {
+
+    let request = match ::std::str::from_utf8(request) {
+        Ok(r)  => r,
+        Err(_) => return LookupResult::PermFail("request is not valid UTF-8".to_string()),
+    };
+
+    let space = match request.find(" ") {
+        Some(pos) => pos,
+        None      => return LookupResult::PermFail("missing lookup key".to_string()),
+    };
+
+    return match map_table(&request[..space]) {
+        Some((table, recipient_domain)) => lookup(table, &request[space+1..], recipient_domain),
+        None        => LookupResult::PermFail(format!("unknown map {:?}", &request[..space])),
+    };
+}
+
+fn format_reply(result: &LookupResult) -> String {
+    match *result {
+        LookupResult::Found(ref v)    => format!("OK {}", v),
+        LookupResult::NotFound(_)     => "NOTFOUND ".to_string(),
+        LookupResult::TempFail(ref r) => format!("TEMP {}", r),
+        LookupResult::PermFail(ref r) => format!("PERM {}", r),
+    }
+}
+
+/// Serve socketmap requests read from `reader` until the client closes the connection.
+/// Framing errors are answered with `PERM` and end the connection.
+pub fn serve<R,W,F>(reader: &mut R, writer: &mut W, max_request_len: usize, lookup: F) -> io::Result<()>
+    where R: BufRead,
+          W: Write,
+          F: Fn(Table, &str, Option<&str>) -> LookupResult {
+
+    loop {
+        let request = match try!(read_netstring(reader, max_request_len)) {
+            Ok(Some(r)) => r,
+            Ok(None)    => return Ok(()), // client closed connection
+            Err(e)      => {
+                let reply = LookupResult::PermFail(format!("malformed netstring: {:?}", e));
+                return write_netstring(writer, &format_reply(&reply));
+            },
+        };
+
+        let reply = handle_request(&request, &lookup);
+        try!(write_netstring(writer, &format_reply(&reply)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::io::Cursor;
+
+    use daemon::lookup::{LookupResult,Table};
+    use super::{serve,read_netstring,NetstringErr};
+
+    fn serve_str(input: &str, max_request_len: usize) -> String {
+        let mut reader = Cursor::new(input.as_bytes().to_vec());
+        let mut output = Vec::new();
+        serve(&mut reader, &mut output, max_request_len, |table, key, recipient_domain| match (table, key) {
+            (Table::Forward, "user@a") if recipient_domain == Some("lists.example") => LookupResult::Found("SRS0=HHHH=TT=a=user@lists".to_string()),
+            (Table::Forward, "user@a") => LookupResult::Found("SRS0=HHHH=TT=a=user@b".to_string()),
+            (Table::Reverse, "SRS0=HHHH=TT=a=user@b") => LookupResult::Found("user@a".to_string()),
+            (Table::Reverse, "SRS0=XXXX=TT=a=user@b") => LookupResult::PermFail("hash verification failed".to_string()),
+            (_, "tmp@a") => LookupResult::TempFail("try again".to_string()),
+            _ => LookupResult::NotFound("no such key".to_string()),
+        }).expect("serving from memory should not fail");
+        return String::from_utf8(output).expect("replies should be UTF-8");
+    }
+
+    #[test]
+    fn it_reads_netstrings() {
+        let mut r = Cursor::new("5:hello,0:,".as_bytes().to_vec());
+        assert!(read_netstring(&mut r, 10).unwrap() == Ok(Some("hello".as_bytes().to_vec())));
+        assert!(read_netstring(&mut r, 10).unwrap() == Ok(Some(Vec::new())));
+        assert!(read_netstring(&mut r, 10).unwrap() == Ok(None));
+    }
+
+    #[test]
+    fn it_rejects_malformed_netstrings() {
+        fn read(s: &str) -> Result<Option<Vec<u8>>, NetstringErr> {
+            read_netstring(&mut Cursor::new(s.as_bytes().to_vec()), 10).unwrap()
+        }
+        assert!(read("x:hello,") == Err(NetstringErr::InvalidLength));
+        assert!(read(":hello,") == Err(NetstringErr::InvalidLength));
+        assert!(read("5hello,") == Err(NetstringErr::InvalidLength));
+        assert!(read("11:hello world,") == Err(NetstringErr::TooLong(11)));
+        assert!(read("5:hello;") == Err(NetstringErr::MissingTerminator));
+        assert!(read("5:hel") == Err(NetstringErr::UnexpectedEof));
+    }
+
+    #[test]
+    fn it_dispatches_on_map_name() {
+        let out = serve_str("14:forward user@a,29:reverse SRS0=HHHH=TT=a=user@b,", 100);
+        assert!(out == "24:OK SRS0=HHHH=TT=a=user@b,9:OK user@a,");
+    }
+
+    #[test]
+    fn it_passes_the_recipient_domain_from_the_map_name() {
+        let out = serve_str("28:forward@lists.example user@a,", 100);
+        assert!(out == "28:OK SRS0=HHHH=TT=a=user@lists,");
+        let out = serve_str("15:forward@ user@a,", 100);
+        assert!(out == "27:PERM unknown map \"forward@\",");
+    }
+
+    #[test]
+    fn it_maps_results_to_reply_codes() {
+        let out = serve_str("15:forward other@a,13:forward tmp@a,29:reverse SRS0=XXXX=TT=a=user@b,", 100);
+        assert!(out == "9:NOTFOUND ,14:TEMP try again,29:PERM hash verification failed,");
+    }
+
+    #[test]
+    fn it_rejects_unknown_maps() {
+        let out = serve_str("10:foo user@a,", 100);
+        assert!(out == "22:PERM unknown map \"foo\",");
+    }
+
+    #[test]
+    fn it_stops_on_framing_errors() {
+        let out = serve_str("14:forward user@a,1000:forward ,14:forward user@a,", 100);
+        assert!(out == "24:OK SRS0=HHHH=TT=a=user@b,39:PERM malformed netstring: TooLong(1000),");
+    }
+
+}
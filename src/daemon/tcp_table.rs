@@ -0,0 +1,193 @@
+/* Postfix tcp_table(5) lookup protocol
+ *
+ * Requests and replies are single lines, a connection carries any number of them:
+ *
+ *  get SPACE key NEWLINE            => 200 SPACE value NEWLINE   (found)
+ *  put SPACE key SPACE value NEWLINE   500 SPACE text NEWLINE    (not found / failed)
+ *                                      400 SPACE text NEWLINE    (temporary error)
+ *
+ * Keys, values and texts are URL-style encoded: bytes <= 32, % and >= 127 are sent as %XX.
+ */
+
+use std::io;
+use std::io::{BufRead,Read,Write};
+
+use daemon::lookup::LookupResult;
+
+#[derive(Debug,PartialEq)]
+pub enum DecodeErr {
+    TruncatedEscape,
+    InvalidHexDigit,
+    InvalidUtf8,
+}
+
+pub fn encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        if b <= 32 || b == b'%' || b >= 127 {
+            encoded.push_str(&format!("%{:02X}", b));
+        } else {
+            encoded.push(b as char);
+        }
+    }
+    return encoded;
+}
+
+pub fn decode(s: &str) -> Result<String, DecodeErr> {
+
+    fn hex_value(a: u8) -> Result<u8, DecodeErr> {
+        match a {
+            b'0'...b'9' => Ok(a - b'0'),
+            b'a'...b'f' => Ok(10 + a - b'a'),
+            b'A'...b'F' => Ok(10 + a - b'A'),
+            _           => Err(DecodeErr::InvalidHexDigit),
+        }
+    }
+
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] != b'%' {
+            decoded.push(bytes[idx]);
+            idx += 1;
+            continue;
+        }
+        if idx + 2 >= bytes.len() {
+            return Err(DecodeErr::TruncatedEscape);
+        }
+        let high = try!(hex_value(bytes[idx+1]));
+        let low = try!(hex_value(bytes[idx+2]));
+        decoded.push(high << 4 | low);
+        idx += 3;
+    }
+
+    return String::from_utf8(decoded).map_err(|_| DecodeErr::InvalidUtf8);
+}
+
+fn format_reply(result: &LookupResult) -> String {
+    match *result {
+        LookupResult::Found(ref v)    => format!("200 {}\n", encode(v)),
+        LookupResult::NotFound(ref t) => format!("500 {}\n", encode(t)),
+        LookupResult::PermFail(ref t) => format!("500 {}\n", encode(t)),
+        LookupResult::TempFail(ref t) => format!("400 {}\n", encode(t)),
+    }
+}
+
+fn handle_request<F>(line: &str, lookup: &F) -> LookupResult
+    where F: Fn(&str) -> LookupResult {
+
+    if line.starts_with("get ") {
+        return match decode(&line[4..]) {
+            Ok(key) => lookup(&key),
+            Err(e)  => LookupResult::PermFail(format!("malformed key: {:?}", e)),
+        };
+    }
+
+    if line.starts_with("put ") {
+        return LookupResult::PermFail("table is read-only".to_string());
+    }
+
+    return LookupResult::PermFail("unsupported request".to_string());
+}
+
+/// Serve tcp_table requests read from `reader` until the client closes the connection.
+/// Requests longer than `max_request_len` bytes are answered with an error and end the connection.
+pub fn serve<R,W,F>(reader: &mut R, writer: &mut W, max_request_len: usize, lookup: F) -> io::Result<()>
+    where R: BufRead,
+          W: Write,
+          F: Fn(&str) -> LookupResult {
+
+    let mut buf = Vec::with_capacity(max_request_len);
+
+    loop {
+        buf.clear();
+        let len = try!((&mut *reader).take(max_request_len as u64 + 1).read_until(b'\n', &mut buf));
+        if len == 0 {
+            return Ok(()); // client closed connection
+        }
+
+        if buf.last() != Some(&b'\n') {
+            if len > max_request_len {
+                try!(writer.write_all(format_reply(&LookupResult::PermFail("request too long".to_string())).as_bytes()));
+                try!(writer.flush());
+            }
+            return Ok(()); // cannot resynchronize, or client hung up mid-request
+        }
+
+        let reply = match String::from_utf8(buf.clone()) {
+            Ok(line) => handle_request(line.trim_right_matches(|c| c == '\n' || c == '\r'), &lookup),
+            Err(_)   => LookupResult::PermFail("request is not valid UTF-8".to_string()),
+        };
+
+        try!(writer.write_all(format_reply(&reply).as_bytes()));
+        try!(writer.flush());
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::io::Cursor;
+
+    use daemon::lookup::LookupResult;
+    use super::{encode,decode,serve,DecodeErr};
+
+    fn serve_str(input: &str, max_request_len: usize) -> String {
+        let mut reader = Cursor::new(input.as_bytes().to_vec());
+        let mut output = Vec::new();
+        serve(&mut reader, &mut output, max_request_len, |key| match key {
+            "user@a" => LookupResult::Found("SRS0=HHHH=TT=a=user@b".to_string()),
+            "tmp@a"  => LookupResult::TempFail("try again".to_string()),
+            _        => LookupResult::NotFound("no such key".to_string()),
+        }).expect("serving from memory should not fail");
+        return String::from_utf8(output).expect("replies should be UTF-8");
+    }
+
+    #[test]
+    fn it_encodes_special_chars() {
+        assert!(encode("user@a") == "user@a");
+        assert!(encode("a b%c\n") == "a%20b%25c%0A");
+        assert!(encode("ä") == "%C3%A4");
+    }
+
+    #[test]
+    fn it_decodes_its_encoding() {
+        for s in vec!["", "user@a", "with space", "100%", "tab\there", "ümlaut"] {
+            assert!(decode(&encode(s)) == Ok(s.to_string()));
+        }
+        assert!(decode("a%2fb%2Fc") == Ok("a/b/c".to_string()));
+    }
+
+    #[test]
+    fn it_rejects_malformed_escapes() {
+        assert!(decode("abc%2") == Err(DecodeErr::TruncatedEscape));
+        assert!(decode("abc%zz") == Err(DecodeErr::InvalidHexDigit));
+        assert!(decode("%ff") == Err(DecodeErr::InvalidUtf8));
+    }
+
+    #[test]
+    fn it_answers_multiple_requests_per_connection() {
+        let out = serve_str("get user@a\nget other@a\nget tmp@a\n", 100);
+        assert!(out == "200 SRS0=HHHH=TT=a=user@b\n500 no%20such%20key\n400 try%20again\n");
+    }
+
+    #[test]
+    fn it_decodes_keys() {
+        let out = serve_str("get user%40a\r\n", 100);
+        assert!(out == "200 SRS0=HHHH=TT=a=user@b\n");
+    }
+
+    #[test]
+    fn it_refuses_put_and_unknown_requests() {
+        let out = serve_str("put user@a value\nfoo bar\n", 100);
+        assert!(out == "500 table%20is%20read-only\n500 unsupported%20request\n");
+    }
+
+    #[test]
+    fn it_stops_on_overlong_requests() {
+        let out = serve_str("get user@a\nget aaaaaaaaaaaaaaaaaaaaaaaaaaaaa\nget user@a\n", 16);
+        assert!(out == "200 SRS0=HHHH=TT=a=user@b\n500 request%20too%20long\n");
+    }
+
+}
@@ -0,0 +1,3 @@
+extern crate openssl;
+
+pub mod srs;
@@ -1,103 +1,358 @@
 extern crate getopts;
 extern crate openssl;
+extern crate libc;
+extern crate rustsrsd;
 
-mod srs;
+mod daemon;
 
-use getopts::{Options,HasArg,Occur};
-use openssl::hash::MessageDigest;
+use rustsrsd::srs;
+
+use getopts::{Options,HasArg,Occur,Matches};
 
 use std::env;
 
-use std::net::*;
-use std::io::{Read,Write};
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
 use std::process;
+use std::sync::{Arc,RwLock};
+use std::thread;
+use std::time::{Duration,Instant};
 
-use srs::parser::{SRSAddress};
+use srs::parser::Charset;
+use srs::policy::{Policy,Rule,Matcher,Action};
+use srs::secrets::Secrets;
+use srs::database::{DatabaseForwarder,DatabaseReceiver,FileStore};
+use srs::transformers::{Timestamper,SRSTimestamper,HourlyTimestamper,NoTimestamper,LibSRS2Timestamper,HashFormat,HashEncoding,OverflowStrategy,SRSScheme};
+use daemon::config::{self,Config,Value};
+use daemon::lookup::{Lookup,Table};
+use daemon::signals;
+use daemon::listener::{ListenAddr,Listener};
+use daemon::server::{self,Protocol,ServerConfig,ConnectionLimit,SharedLookup};
 
 fn print_usage(program: &str, opts: Options) {
     let brief = format!("Usage: {} [options]", program);
     print!("{}", opts.usage(&brief));
 }
 
+type BoxedTimestamper = Box<Timestamper + Send + Sync>;
+
+fn make_timestamper(config: &Config) -> BoxedTimestamper {
+    match (config.compatibility.as_str(), config.timestamp.as_str()) {
+        ("libsrs2", _) => Box::new(LibSRS2Timestamper::new(config.max_age)),
+        (_, "hours")   => {
+            let mut t = HourlyTimestamper::new(config.max_age);
+            t.max_future_delta = config.max_future_skew;
+            Box::new(t)
+        },
+        (_, "none")    => Box::new(NoTimestamper),
+        _              => {
+            let mut t = SRSTimestamper::new(config.max_age);
+            t.max_future_delta = config.max_future_skew;
+            Box::new(t)
+        },
+    }
+}
+
+/// Skipping rules come first, so they also apply to mail for recipients with their own SRS domain
+fn make_policy(config: &Config) -> Policy {
+    let mut rules = vec![Rule{ name: "null_sender".to_string(), matcher: Matcher::NullSender, action: Action::Skip }];
+    if config.policy.skip_postmaster {
+        rules.push(Rule{ name: "postmaster".to_string(), matcher: Matcher::Postmaster, action: Action::Skip });
+    }
+    let local_domains: Vec<String> = config.policy.local_domains.iter().chain(&config.exclude_domains).cloned().collect();
+    if !local_domains.is_empty() {
+        rules.push(Rule{ name: "local_domains".to_string(), matcher: Matcher::SenderDomain(local_domains), action: Action::Skip });
+    }
+    if !config.policy.no_spf_domains.is_empty() {
+        rules.push(Rule{
+            name: "no_spf_domains".to_string(),
+            matcher: Matcher::SenderDomain(config.policy.no_spf_domains.clone()),
+            action: Action::Skip,
+        });
+    }
+    for &(ref recipient, ref srs) in &config.policy.srs_domains {
+        rules.push(Rule{
+            name: format!("srs_domains {}", recipient),
+            matcher: Matcher::RecipientDomain(vec![recipient.clone()]),
+            action: Action::RewriteAs(srs.clone()),
+        });
+    }
+    return Policy{ rules: rules };
+}
+
+fn make_lookup(config: &Config) -> Result<Lookup<BoxedTimestamper>, String> {
+
+    // Secrets files are concatenated: the first secret of the first file signs
+    let mut secrets: Option<Secrets> = None;
+    for path in &config.secret_files {
+        let s = try!(Secrets::load(path).map_err(|e| format!("cannot load secrets file {:?}: {:?}", path, e)));
+        match secrets {
+            Some(ref mut all) => all.extend(s),
+            None              => secrets = Some(s),
+        }
+    }
+    let secrets = secrets.expect("validated config has a secrets file");
+    let domain = config.domain.clone().expect("validated config has a domain").into_bytes();
+    let md = config::message_digest(&config.hash_algorithm).expect("validated config has a known hash algorithm");
+
+    let mut r = try!(secrets.receiver(
+        domain.clone(),
+        md,
+        make_timestamper(config),
+    ).map_err(|e| format!("cannot create receiver: {}", e)));
+    let mut f = try!(secrets.forwarder(
+        domain,
+        md,
+        &config.separator,
+        make_timestamper(config),
+    ).map_err(|e| format!("cannot create forwarder: {}", e)));
+
+    f.hash_length = config.hash_length;
+    f.hash_min_length = config.hash_min_length.unwrap_or(config.hash_length);
+    r.hash_length = config.hash_length;
+    r.hash_min_length = config.hash_min_length.unwrap_or(config.hash_length);
+    let scheme = match config.scheme.as_str() {
+        "shortcut"   => SRSScheme::Shortcut,
+        "reversible" => SRSScheme::Reversible,
+        _            => SRSScheme::Guarded,
+    };
+    f.scheme = scheme;
+    r.scheme = scheme;
+    f.strict = config.strict_srs1;
+    f.overflow_strategy = match config.overflow_strategy.as_str() {
+        "compress" => OverflowStrategy::Compress,
+        "database" => OverflowStrategy::Database,
+        _          => OverflowStrategy::Fail,
+    };
+    let hash_format = match config.compatibility.as_str() {
+        "libsrs2" => HashFormat::LibSRS2,
+        _         => HashFormat::Native,
+    };
+    f.hash_format = hash_format;
+    r.hash_format = hash_format;
+    let hash_encoding = match config.hash_encoding.as_str() {
+        "base32" => HashEncoding::Base32,
+        _        => HashEncoding::Base64,
+    };
+    f.hash_encoding = hash_encoding;
+    r.hash_encoding = hash_encoding;
+    f.fold_case = config.fold_case;
+    r.fold_case = config.fold_case;
+
+    // Addresses issued under per-recipient SRS domains are ours, too
+    f.own_domains.extend(config.policy.srs_domains.iter().map(|&(_, ref srs)| srs.clone()));
+
+    let mut lookup = Lookup::new(f, r);
+    lookup.policy = make_policy(config);
+    if config.smtputf8 {
+        lookup.charset = Charset::SMTPUTF8;
+        lookup.forwarder.charset = Charset::SMTPUTF8;
+    }
+
+    // Also answer reverse lookups for stored senders if the strategy was changed since
+    if let Some(ref dir) = config.database_dir {
+        let store = try!(FileStore::new(dir.clone()).map_err(|e| format!("cannot open database {:?}: {}", dir, e)));
+        let domain = config.domain.clone().expect("validated config has a domain");
+        lookup.database_forwarder = Some(try!(DatabaseForwarder::new(store.clone(), domain, &config.separator, make_timestamper(config))
+            .map_err(|e| format!("cannot create database forwarder: {}", e))));
+        lookup.database_receiver = Some(DatabaseReceiver::new(store, make_timestamper(config)));
+    }
+
+    return Ok(lookup);
+}
+
+// Command line flags and the configuration keys they override
+const CLI_OVERRIDES: &'static [(&'static str, &'static str)] = &[
+    ("domain",            "domain"),
+    ("separator",         "separator"),
+    ("scheme",            "scheme"),
+    ("strict-srs1",       "strict_srs1"),
+    ("compatibility",     "compatibility"),
+    ("hash-algorithm",    "hash_algorithm"),
+    ("fold-case",         "fold_case"),
+    ("smtputf8",          "smtputf8"),
+    ("hash-encoding",     "hash_encoding"),
+    ("hash-length",       "hash_length"),
+    ("hash-min-length",   "hash_min_length"),
+    ("timestamp",         "timestamp"),
+    ("max-age",           "max_age"),
+    ("max-future-skew",   "max_future_skew"),
+    ("overflow-strategy", "overflow_strategy"),
+    ("database-dir",      "database_dir"),
+    ("listen.recv",       "listen.recv"),
+    ("listen.send",       "listen.send"),
+    ("listen.socketmap",  "listen.socketmap"),
+    ("unix.owner",        "unix.owner"),
+    ("unix.group",        "unix.group"),
+    ("unix.mode",         "unix.mode"),
+    ("bufsize",           "max_request_len"),
+    ("max-connections",   "max_connections"),
+    ("idle-timeout",      "idle_timeout"),
+];
+
+fn load_config(matches: &Matches) -> Result<Config, String> {
+
+    let mut config = match matches.opt_str("config") {
+        None       => Config::default(),
+        Some(path) => {
+            let mut text = String::new();
+            try!(fs::File::open(&path)
+                 .and_then(|mut f| f.read_to_string(&mut text))
+                 .map_err(|e| format!("cannot read config file {}: {}", path, e)));
+            try!(Config::from_str(&text).map_err(|e| format!("{}: {}", path, e)))
+        },
+    };
+
+    for &(flag, key) in CLI_OVERRIDES {
+        if let Some(v) = matches.opt_str(flag) {
+            try!(config.set_from_cli(key, &v).map_err(|e| e.to_string()));
+        }
+    }
+    if matches.opt_present("secret-file") {
+        let files = Value::List(matches.opt_strs("secret-file"));
+        try!(config.set("secret_files", files).map_err(|e| format!("secret-file: {}", e)));
+    }
+    if matches.opt_present("exclude-domain") {
+        let domains = Value::List(matches.opt_strs("exclude-domain"));
+        try!(config.set("exclude_domains", domains).map_err(|e| format!("exclude-domain: {}", e)));
+    }
+
+    try!(config.validate().map_err(|e| e.to_string()));
+    return Ok(config);
+}
+
+// Seconds between removals of expired database entries
+const DATABASE_EXPIRY_INTERVAL: u64 = 60 * 60;
+
+fn remove_sockets(paths: &[PathBuf]) {
+    for path in paths {
+        if let Err(e) = fs::remove_file(path) {
+            println!("cannot remove socket {:?}: {}", path, e);
+        }
+    }
+}
+
 fn main() {
 
     let args: Vec<String> = env::args().collect();
 
     let mut opts = Options::new();
-    opts.opt("", "listen.recv", "listen for receivers", "", HasArg::Yes, Occur::Req);
-    opts.opt("", "listen.send", "listen for receivers", "", HasArg::Yes, Occur::Req);
-    opts.opt("s", "bufsize", "max buf size in bytes", "BYTES", HasArg::Yes, Occur::Req);
+    opts.opt("c", "config", "configuration file, overridden by the flags below", "FILE", HasArg::Yes, Occur::Optional);
+    opts.opt("", "domain", "SRS domain", "DOMAIN", HasArg::Yes, Occur::Optional);
+    opts.opt("", "secret-file", "secrets file, one secret per line (newest first), reloaded on SIGHUP", "FILE", HasArg::Yes, Occur::Multi);
+    opts.opt("", "separator", "SRS separator (=, + or -)", "SEP", HasArg::Yes, Occur::Optional);
+    opts.opt("", "scheme", "guarded (default), shortcut or reversible", "SCHEME", HasArg::Yes, Occur::Optional);
+    opts.opt("", "strict-srs1", "verify SRS1 addresses of our domain and re-sign others (true or false)", "BOOL", HasArg::Yes, Occur::Optional);
+    opts.opt("", "compatibility", "native (default) or libsrs2 to interoperate with postsrsd", "MODE", HasArg::Yes, Occur::Optional);
+    opts.opt("", "hash-algorithm", "HMAC hash algorithm (default sha512)", "ALG", HasArg::Yes, Occur::Optional);
+    opts.opt("", "fold-case", "hash lowercased addresses (true or false)", "BOOL", HasArg::Yes, Occur::Optional);
+    opts.opt("", "smtputf8", "accept UTF-8 addresses (true or false)", "BOOL", HasArg::Yes, Occur::Optional);
+    opts.opt("", "hash-encoding", "base64 (default) or base32, which survives a change of case", "ENC", HasArg::Yes, Occur::Optional);
+    opts.opt("", "hash-length", "length of issued SRS hashes (default 4)", "CHARS", HasArg::Yes, Occur::Optional);
+    opts.opt("", "hash-min-length", "shortest SRS hash accepted (default: hash-length)", "CHARS", HasArg::Yes, Occur::Optional);
+    opts.opt("", "timestamp", "days (default), hours or none, which never expires", "RESOLUTION", HasArg::Yes, Occur::Optional);
+    opts.opt("", "max-age", "timestamp validity window (default 21)", "DAYS|HOURS", HasArg::Yes, Occur::Optional);
+    opts.opt("", "max-future-skew", "accept timestamps up to this far in the future (default 1)", "DAYS|HOURS", HasArg::Yes, Occur::Optional);
+    opts.opt("", "overflow-strategy", "fail (default), compress (needs hash-min-length below hash-length) or database if the SRS local part exceeds 64 octets", "STRATEGY", HasArg::Yes, Occur::Optional);
+    opts.opt("", "database-dir", "directory storing senders for the database overflow strategy", "DIR", HasArg::Yes, Occur::Optional);
+    opts.opt("", "exclude-domain", "never rewrite senders from this domain, .domain for subdomains", "DOMAIN", HasArg::Yes, Occur::Multi);
+    opts.opt("", "listen.recv", "listen for receivers (tcp_table, recipient_canonical_maps)", "ADDR", HasArg::Yes, Occur::Optional);
+    opts.opt("", "listen.send", "listen for senders (tcp_table, sender_canonical_maps)", "ADDR", HasArg::Yes, Occur::Optional);
+    opts.opt("", "listen.socketmap", "listen for socketmap lookups (maps 'forward', 'forward@RECIPIENT-DOMAIN' and 'reverse')", "ADDR", HasArg::Yes, Occur::Optional);
+    opts.opt("", "unix.owner", "owner of unix sockets", "USER", HasArg::Yes, Occur::Optional);
+    opts.opt("", "unix.group", "group of unix sockets", "GROUP", HasArg::Yes, Occur::Optional);
+    opts.opt("", "unix.mode", "mode of unix sockets", "OCTAL", HasArg::Yes, Occur::Optional);
+    opts.opt("s", "bufsize", "max request size in bytes (default 4096)", "BYTES", HasArg::Yes, Occur::Optional);
+    opts.opt("", "max-connections", "max number of concurrently served connections (default 100)", "N", HasArg::Yes, Occur::Optional);
+    opts.opt("", "idle-timeout", "close connections idle for this long, 0 disables (default 300)", "SECONDS", HasArg::Yes, Occur::Optional);
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(e) => {
+            println!("{}", e);
             print_usage(&args[0], opts);
             process::exit(1);
         }
     };
 
-    let listen_addr  = matches.opt_str("listen.recv").expect("required option not found");
-    let listener = TcpListener::bind(listen_addr.as_str()).expect("specified listen addr must be bindable");
+    let config = match load_config(&matches) {
+        Ok(c)  => c,
+        Err(e) => {
+            println!("invalid configuration: {}", e);
+            process::exit(1);
+        },
+    };
+
+    let lookup: SharedLookup<BoxedTimestamper> = match make_lookup(&config) {
+        Ok(l)  => Arc::new(RwLock::new(Arc::new(l))),
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        },
+    };
+
+    let server_config = ServerConfig{
+        max_request_len: config.max_request_len,
+        idle_timeout: match config.idle_timeout {
+            0 => None,
+            n => Some(Duration::from_secs(n)),
+        },
+    };
+    let limit = Arc::new(ConnectionLimit::new(config.max_connections));
+
+    let listeners = vec![
+        (Protocol::TcpTable(Table::Reverse), config.listen.recv.clone()),
+        (Protocol::TcpTable(Table::Forward), config.listen.send.clone()),
+        (Protocol::Socketmap, config.listen.socketmap.clone()),
+    ];
 
-    for stream in listener.incoming() {
-        match stream {
+    signals::install_shutdown_handler();
+    signals::install_reload_handler();
+
+    let mut socket_paths = Vec::new();
+    for (protocol, listen_addr) in listeners {
+        let listen_addr = match listen_addr {
+            Some(a) => a,
+            None    => continue,
+        };
+        let listener = match Listener::bind(&ListenAddr::parse(&listen_addr), &config.unix) {
+            Ok(l)  => l,
             Err(e) => {
-                println!("Error accepting connection");
+                println!("cannot listen on {}: {}", listen_addr, e);
+                remove_sockets(&socket_paths);
+                process::exit(1);
             },
-            Ok(mut stream) => {
-                println!("Connection from {:?}", stream.peer_addr());
-
-                let mut buf = Vec::new();
-                let mut bufsize: usize = matches.opt_str("bufsize").unwrap().parse().expect("bufsize must be an unsigned integer");
-                buf.resize(bufsize, 0);
-
-                let input = match stream.read(&mut buf) {
-                    Err(e) => {
-                        println!("error reading: {:?}", e);
-                        continue;
-                    },
-                    Ok(len) => {
-                        String::from_utf8_lossy(&buf[..len])
-                    }
-                };
-
-                if !input.starts_with("get ") {
-                    println!("invalid request format");
-                    continue;
-                }
+        };
+        socket_paths.extend(listener.socket_path());
+        let lookup = lookup.clone();
+        let limit = limit.clone();
+        thread::spawn(move || server::run(listener, protocol, lookup, limit, server_config));
+    }
 
-                use srs::transformers::{Receiver,SRSTimestamper};
-                let r = Receiver::new(
-                    vec![0,0,0,0],
-                    vec![0xb, 0xa, 0xd, 0xf, 0x0, 0x0, 0xd],
-                    openssl::hash::MessageDigest::sha512(),
-                    SRSTimestamper{max_valid_delta: 5},
-                );
-                let r = match r {
-                    Err(x) => {
-                        println!("cannot create receiver: {:?}", x);
-                        continue;
-                    },
-                    Ok(r) => r,
-                };
-
-
-                let addr = SRSAddress::from_string(input[4..].trim_right());
-
-                match addr {
-                    Err(e) => {
-                        println!("error parsing: {:?}", e);
-                    },
-                    Ok(a) => {
-                        let res = r.receive(&a);
-                        println!("SRS output: {:?}", res);
-                    }
+    let mut last_expiry = Instant::now();
+    while !signals::shutdown_requested() {
+        thread::sleep(Duration::from_millis(200));
+        if last_expiry.elapsed() >= Duration::from_secs(DATABASE_EXPIRY_INTERVAL) {
+            last_expiry = Instant::now();
+            if let Some(ref db) = server::current_lookup(&lookup).database_receiver {
+                match db.expire() {
+                    Ok(n)  => println!("expired {} database entries", n),
+                    Err(e) => println!("cannot expire database entries: {}", e),
                 }
-
-                stream.shutdown(Shutdown::Both);
             }
         }
-
+        if signals::take_reload_request() {
+            match make_lookup(&config) {
+                Ok(l)  => {
+                    *lookup.write().expect("lookup lock poisoned") = Arc::new(l);
+                    println!("reloaded secrets");
+                },
+                Err(e) => println!("cannot reload secrets, keeping the current ones: {}", e),
+            }
+        }
     }
+    println!("shutting down");
+    remove_sockets(&socket_paths);
 
 }
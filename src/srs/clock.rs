@@ -0,0 +1,62 @@
+use std::time;
+
+/// Source of the current time for timestamps, replaceable to test on specific dates
+pub trait Clock {
+    /// Seconds since the UNIX epoch
+    fn now(&self) -> u64;
+
+    /// Days since the UNIX epoch, modulo 1024 to fit into the 10 bit of a timestamp
+    fn days_10bit(&self) -> u16 {
+        // => wraparound after > 3 years is fine
+        return ((self.now() / (60 * 60 * 24)) % 1024) as u16;
+    }
+}
+
+#[derive(Clone,Copy,Debug,Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        return time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .expect("UNIX_EPOCH is always earlier than current time")
+            .as_secs();
+    }
+}
+
+/// Always returns the same time, e.g. the date a bounce was captured
+#[derive(Clone,Copy,Debug)]
+pub struct FixedClock {
+    /// Seconds since the UNIX epoch
+    pub now: u64,
+}
+
+impl FixedClock {
+    /// Midnight of day `days` since the UNIX epoch
+    pub fn at_day(days: u64) -> FixedClock {
+        return FixedClock{ now: days * 60 * 60 * 24 };
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> u64 {
+        return self.now;
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{Clock,FixedClock};
+
+    #[test]
+    fn it_counts_days_modulo_1024() {
+        assert!(FixedClock{ now: 0 }.days_10bit() == 0);
+        assert!(FixedClock{ now: 86399 }.days_10bit() == 0);
+        assert!(FixedClock::at_day(1023).days_10bit() == 1023);
+        assert!(FixedClock::at_day(1024).days_10bit() == 0);
+        // 2017-03-01
+        assert!(FixedClock{ now: 1488326400 }.days_10bit() == 17226 % 1024);
+    }
+
+}
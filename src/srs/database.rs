@@ -0,0 +1,360 @@
+/* Database-backed SRS, like libsrs2's SRS::DB
+ *
+ * Instead of encoding the original sender in the local part, the forwarder stores it
+ * under a random ID and issues SRS0=ID@domain. This hides the original address and
+ * keeps the local part short no matter how long the original address is.
+ * The receiver looks the ID up again, entries expire with the Timestamper's window.
+ *
+ * The price is state: the store must be shared by all hosts receiving bounces.
+ */
+
+extern crate openssl;
+
+use self::openssl::rand::rand_bytes;
+
+use std::ascii::AsciiExt;
+use std::fs;
+use std::io;
+use std::io::{Read,Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+use srs::error::SRSError;
+use srs::parser::Charset;
+use srs::syntax;
+use srs::transformers::{Timestamper,is_valid_srs_separator};
+use srs::util::base32_email_safe_encode;
+
+// 16 random bytes = 26 base32 chars
+const ID_BYTES: usize = 16;
+const ID_LEN: usize = 26;
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct StoredSender {
+    pub sender: String,
+    /// Timestamp of when the entry was made, see `Timestamper`
+    pub tt: String,
+}
+
+/// Storage for the database-backed forwarder and receiver.
+/// IDs are lowercase base32 and safe to use as file names.
+pub trait SRSStore {
+    fn insert(&self, id: &str, entry: &StoredSender) -> io::Result<()>;
+    fn get(&self, id: &str) -> io::Result<Option<StoredSender>>;
+    fn remove(&self, id: &str) -> io::Result<()>;
+    fn ids(&self) -> io::Result<Vec<String>>;
+}
+
+fn is_valid_id(id: &str) -> bool {
+    return id.len() == ID_LEN && id.bytes().all(|b| (b >= b'a' && b <= b'z') || (b >= b'2' && b <= b'7'));
+}
+
+/// One file per ID in a directory, holding the timestamp and the sender on two lines.
+#[derive(Clone,Debug)]
+pub struct FileStore {
+    pub dir: PathBuf,
+}
+
+impl FileStore {
+
+    pub fn new(dir: PathBuf) -> io::Result<FileStore> {
+        try!(fs::create_dir_all(&dir));
+        return Ok(FileStore{ dir: dir });
+    }
+
+    fn path(&self, id: &str) -> io::Result<PathBuf> {
+        if !is_valid_id(id) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid SRS database id"));
+        }
+        return Ok(self.dir.join(id));
+    }
+
+}
+
+impl SRSStore for FileStore {
+
+    fn insert(&self, id: &str, entry: &StoredSender) -> io::Result<()> {
+        let path = try!(self.path(id));
+        // Write a temporary file and rename it so readers never see partial entries
+        let tmp = self.dir.join(format!(".{}.tmp", id));
+        {
+            let mut f = try!(fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&tmp));
+            try!(write!(f, "{}\n{}\n", entry.tt, entry.sender));
+        }
+        return fs::rename(&tmp, &path);
+    }
+
+    fn get(&self, id: &str) -> io::Result<Option<StoredSender>> {
+        let path = try!(self.path(id));
+        let mut content = String::new();
+        match fs::File::open(&path) {
+            Ok(mut f)  => try!(f.read_to_string(&mut content)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e)     => return Err(e),
+        };
+        let mut lines = content.lines();
+        return match (lines.next(), lines.next()) {
+            (Some(tt), Some(sender)) => Ok(Some(StoredSender{ sender: sender.to_string(), tt: tt.to_string() })),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("malformed SRS database entry {:?}", path))),
+        };
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        match fs::remove_file(try!(self.path(id))) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            x => return x,
+        }
+    }
+
+    fn ids(&self) -> io::Result<Vec<String>> {
+        let mut ids = Vec::new();
+        for entry in try!(fs::read_dir(&self.dir)) {
+            if let Ok(name) = try!(entry).file_name().into_string() {
+                if is_valid_id(&name) {
+                    ids.push(name);
+                }
+            }
+        }
+        return Ok(ids);
+    }
+
+}
+
+/// Extract the ID from an address issued by `DatabaseForwarder`.
+/// HMAC-based SRS0 addresses never match, their local part contains separators.
+pub fn parse_id(address: &str) -> Option<&str> {
+    let bytes = address.as_bytes();
+    if bytes.len() < 5 || !bytes[..4].eq_ignore_ascii_case(b"SRS0")
+        || !address.is_char_boundary(5) || !is_valid_srs_separator(&address[4..5]) {
+        return None;
+    }
+    let local = match address[5..].find('@') {
+        Some(pos) => &address[5..5+pos],
+        None      => return None,
+    };
+    if !is_valid_id(&local.to_ascii_lowercase()) {
+        return None;
+    }
+    return Some(local);
+}
+
+pub struct DatabaseForwarder<S,T> where S: SRSStore, T: Timestamper {
+    pub store: S,
+    pub hostname: String,
+    pub separator: String,
+    pub timestamper: T,
+}
+
+impl<S,T> DatabaseForwarder<S,T> where S: SRSStore, T: Timestamper {
+
+    pub fn new(store: S, hostname: String, separator: &str, timestamper: T) -> Result<DatabaseForwarder<S,T>, SRSError> {
+        if !is_valid_srs_separator(separator) {
+            return Err(SRSError::InvalidSeparator);
+        }
+        if !syntax::is_valid_domain(&hostname, Charset::ASCII) {
+            return Err(SRSError::InvalidHostname);
+        }
+        return Ok(DatabaseForwarder{
+            store: store,
+            hostname: hostname,
+            separator: separator.to_string(),
+            timestamper: timestamper,
+        });
+    }
+
+    /// Store `sender` and return the address to use in its place.
+    pub fn forward(&self, sender: &str) -> Result<String, SRSError> {
+        return self.forward_as(sender, &self.hostname);
+    }
+
+    /// Like `forward`, issuing the address under `hostname` instead of ours.
+    pub fn forward_as(&self, sender: &str, hostname: &str) -> Result<String, SRSError> {
+        if !syntax::is_valid_domain(hostname, Charset::ASCII) {
+            return Err(SRSError::InvalidHostname);
+        }
+        if sender.contains(|c| c == '\r' || c == '\n') {
+            return Err(SRSError::InvalidSender);
+        }
+
+        let mut random = [0u8; ID_BYTES];
+        try!(rand_bytes(&mut random).map_err(SRSError::RandomError));
+        let mut id = base32_email_safe_encode(&random);
+        id.truncate(ID_LEN);
+
+        let entry = StoredSender{
+            sender: sender.to_string(),
+            tt: self.timestamper.now_as_timestamp(),
+        };
+        try!(self.store.insert(&id, &entry).map_err(SRSError::StoreError));
+
+        return Ok(format!("SRS0{}{}@{}", self.separator, id, hostname));
+    }
+
+}
+
+pub struct DatabaseReceiver<S,T> where S: SRSStore, T: Timestamper {
+    pub store: S,
+    pub timestamper: T,
+}
+
+impl<S,T> DatabaseReceiver<S,T> where S: SRSStore, T: Timestamper {
+
+    pub fn new(store: S, timestamper: T) -> DatabaseReceiver<S,T> {
+        return DatabaseReceiver{
+            store: store,
+            timestamper: timestamper,
+        };
+    }
+
+    /// Look up the original sender, removing the entry if it expired.
+    pub fn receive(&self, address: &str) -> Result<String, SRSError> {
+        let id = match parse_id(address) {
+            Some(id) => id.to_ascii_lowercase(),
+            None     => return Err(SRSError::NotADatabaseAddress),
+        };
+        let entry = match try!(self.store.get(&id).map_err(SRSError::StoreError)) {
+            Some(e) => e,
+            None    => return Err(SRSError::UnknownId),
+        };
+        if let Err(e) = self.timestamper.verify_timestamp(&entry.tt) {
+            try!(self.store.remove(&id).map_err(SRSError::StoreError));
+            return Err(e);
+        }
+        return Ok(entry.sender);
+    }
+
+    /// Remove all expired entries and return how many there were.
+    /// Timestamps wrap around, so this must run more often than that.
+    pub fn expire(&self) -> Result<usize, SRSError> {
+        let mut expired = 0;
+        for id in try!(self.store.ids().map_err(SRSError::StoreError)) {
+            let entry = match try!(self.store.get(&id).map_err(SRSError::StoreError)) {
+                Some(e) => e,
+                None    => continue, // removed concurrently
+            };
+            if self.timestamper.verify_timestamp(&entry.tt).is_err() {
+                try!(self.store.remove(&id).map_err(SRSError::StoreError));
+                expired += 1;
+            }
+        }
+        return Ok(expired);
+    }
+
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use srs::error::SRSError;
+    use srs::transformers::Timestamper;
+    use super::{DatabaseForwarder,DatabaseReceiver,FileStore,SRSStore};
+
+    struct FixedTimestamper {
+        now: &'static str,
+    }
+    impl Timestamper for FixedTimestamper {
+        fn verify_timestamp(&self, ts: &str) -> Result<(), SRSError> {
+            match ts == self.now {
+                true  => Ok(()),
+                false => Err(SRSError::TimestampExpired{ age: 23 }),
+            }
+        }
+        fn now_as_timestamp(&self) -> String { self.now.to_string() }
+    }
+
+    fn make_store(name: &str) -> FileStore {
+        let mut p = env::temp_dir();
+        p.push(format!("rustsrsd-test-{}-{}.db", process::id(), name));
+        let _ = fs::remove_dir_all(&p);
+        return FileStore::new(p).expect("should create store directory");
+    }
+
+    fn make_pair(store: &FileStore) -> (DatabaseForwarder<FileStore,FixedTimestamper>, DatabaseReceiver<FileStore,FixedTimestamper>) {
+        let f = DatabaseForwarder::new(store.clone(), "b".to_string(), "=", FixedTimestamper{ now: "aa" })
+            .expect("test should supply valid forwarder params");
+        let r = DatabaseReceiver::new(store.clone(), FixedTimestamper{ now: "aa" });
+        return (f, r);
+    }
+
+    #[test]
+    fn it_round_trips_through_the_store() {
+        let store = make_store("roundtrip");
+        let (f, r) = make_pair(&store);
+        let srs = f.forward("some.very.long.local.part@origin.example.com").expect("should forward");
+        assert!(srs.starts_with("SRS0="));
+        assert!(srs.ends_with("@b"));
+        assert!(!srs.contains("origin"));
+        assert!(r.receive(&srs).expect("should receive") == "some.very.long.local.part@origin.example.com");
+        // MTAs may change the case
+        assert!(r.receive(&srs.to_uppercase()).is_ok());
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn it_issues_distinct_ids() {
+        let store = make_store("distinct");
+        let (f, _) = make_pair(&store);
+        let a = f.forward("user@origin").unwrap();
+        let b = f.forward("user@origin").unwrap();
+        assert!(a != b);
+        assert!(store.ids().unwrap().len() == 2);
+        match f.forward("user@origin\nSRS0=x@b") {
+            Err(SRSError::InvalidSender) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn it_issues_under_other_domains() {
+        let store = make_store("other-domain");
+        let (f, r) = make_pair(&store);
+        let srs = f.forward_as("user@origin", "srs.example.net").unwrap();
+        assert!(srs.ends_with("@srs.example.net"));
+        assert!(r.receive(&srs).unwrap() == "user@origin");
+        match f.forward_as("user@origin", "srs_example") {
+            Err(SRSError::InvalidHostname) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn it_rejects_unknown_and_foreign_addresses() {
+        let store = make_store("unknown");
+        let (_, r) = make_pair(&store);
+        match r.receive("SRS0=aaaaaaaaaaaaaaaaaaaaaaaaaa@b") {
+            Err(SRSError::UnknownId) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+        for a in &["SRS0=M59m=TT=a=user@b", "SRS0=../../etc/passwd@b", "user@b", "SRS0=", "SRS\u{e9}x@b", "SRS0\u{e9}x@b"] {
+            match r.receive(a) {
+                Err(SRSError::NotADatabaseAddress) => (),
+                x => panic!("unexpected result for {}: {:?}", a, x),
+            }
+        }
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn it_expires_entries() {
+        let store = make_store("expire");
+        let (f, mut r) = make_pair(&store);
+        let srs = f.forward("user@origin").unwrap();
+        f.forward("other@origin").unwrap();
+        r.timestamper.now = "ab";
+        match r.receive(&srs) {
+            Err(SRSError::TimestampExpired{ age: 23 }) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+        assert!(store.ids().unwrap().len() == 1);
+        assert!(r.expire().unwrap() == 1);
+        assert!(store.ids().unwrap().is_empty());
+        fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+}
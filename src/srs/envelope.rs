@@ -0,0 +1,196 @@
+/* Envelope sender parsing
+ *
+ * Turns a MAIL FROM value as written by the client, with or without angle brackets,
+ * into a ForwardableAddress. Source routes are dropped as RFC 5321 allows, quoted
+ * local parts are kept as written and the domain may be an address literal.
+ */
+
+use srs::error::{SRSError,Component};
+use srs::parser::{SRSAddress,Charset};
+use srs::syntax;
+use srs::transformers::ForwardableAddress;
+
+fn malformed(component: Component, offset: usize) -> SRSError {
+    return SRSError::Malformed{ component: component, offset: offset };
+}
+
+/// Characters never valid outside of quotes
+fn is_forbidden(c: char, charset: Charset) -> bool {
+    return c.is_control() || c.is_whitespace() || "<>()[]\\,;:\"".contains(c)
+        || (charset == Charset::ASCII && !c.is_ascii());
+}
+
+/// Position of the `@` after a quoted local part starting at `mailbox[0]`
+fn quoted_local_end(mailbox: &str) -> Result<usize, (Component, usize)> {
+    let bytes = mailbox.as_bytes();
+    let mut idx = 1;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'\\' => idx += 2, // quoted-pair
+            b'"'  => break,
+            _     => idx += 1,
+        }
+    }
+    if idx >= bytes.len() {
+        return Err((Component::Local, bytes.len()));
+    }
+    if bytes.get(idx + 1) != Some(&b'@') {
+        return Err((Component::Local, idx + 1));
+    }
+    return Ok(idx + 1);
+}
+
+/// Parse any envelope sender, e.g. `<"john doe"@example.com>`, `user@[192.0.2.1]` or `<>`.
+/// Non-ASCII senders are only accepted with `Charset::SMTPUTF8`.
+/// Offsets in errors are relative to `input`.
+pub fn parse_envelope_sender(input: &str, charset: Charset) -> Result<ForwardableAddress, SRSError> {
+
+    let (mut start, end) = match input.starts_with('<') {
+        true if input.len() >= 2 && input.ends_with('>') => (1, input.len() - 1),
+        true  => return Err(malformed(Component::Brackets, input.len())),
+        false => (0, input.len()),
+    };
+
+    if start == end {
+        return Ok(ForwardableAddress::Null);
+    }
+
+    // Source route: @relay1,@relay2:user@domain
+    if input[start..end].starts_with('@') {
+        match input[start..end].find(':') {
+            Some(colon) => start += colon + 1,
+            None        => return Err(malformed(Component::Route, start)),
+        }
+    }
+
+    let mailbox = &input[start..end];
+
+    let at = match mailbox.starts_with('"') {
+        true  => try!(quoted_local_end(mailbox).map_err(|(c, offset)| malformed(c, start + offset))),
+        false => match mailbox.rfind('@') {
+            Some(at) => at,
+            None     => return Err(malformed(Component::Domain, end)),
+        },
+    };
+    if at == 0 {
+        return Err(malformed(Component::Local, start));
+    }
+
+    let local = &mailbox[..at];
+    let domain = &mailbox[at+1..];
+
+    if !local.starts_with('"') {
+        // The last @ splits, so unquoted local parts may contain more
+        if let Some((pos, _)) = local.char_indices().find(|&(_, c)| c != '@' && is_forbidden(c, charset)) {
+            return Err(malformed(Component::Local, start + pos));
+        }
+    } else if let Some(pos) = local.bytes().position(|b| b >= 0x80 && charset == Charset::ASCII) {
+        return Err(malformed(Component::Local, start + pos));
+    } else if !syntax::is_quoted_string(local, charset) {
+        return Err(malformed(Component::Local, start));
+    }
+
+    let domain_start = start + at + 1;
+    if domain.is_empty() {
+        return Err(malformed(Component::Domain, domain_start));
+    }
+    if domain.starts_with('[') {
+        if !syntax::is_address_literal(domain) {
+            return Err(malformed(Component::Domain, domain_start));
+        }
+    } else if let Some((pos, _)) = domain.char_indices().find(|&(_, c)| c == '@' || is_forbidden(c, charset)) {
+        return Err(malformed(Component::Domain, domain_start + pos));
+    }
+
+    // Anything that does not parse as SRS is forwarded like a plain address
+    if let Ok(srs) = SRSAddress::parse(mailbox, charset) {
+        return Ok(ForwardableAddress::SRS(srs));
+    }
+
+    return Ok(ForwardableAddress::Plain{
+        local: local.to_string(),
+        domain: domain.to_string(),
+    });
+}
+
+#[cfg(test)]
+mod test {
+
+    use srs::error::{SRSError,Component};
+    use srs::parser::Charset;
+    use srs::transformers::ForwardableAddress;
+    use super::parse_envelope_sender;
+
+    fn expect_plain(input: &str, local: &str, domain: &str) {
+        match parse_envelope_sender(input, Charset::ASCII) {
+            Ok(ForwardableAddress::Plain{ local: ref l, domain: ref d }) if l == local && d == domain => (),
+            x => panic!("unexpected result for {:?}: {:?}", input, x),
+        }
+    }
+
+    fn expect_malformed(input: &str, component: Component, offset: usize) {
+        match parse_envelope_sender(input, Charset::ASCII) {
+            Err(SRSError::Malformed{ component: c, offset: o }) if c == component && o == offset => (),
+            x => panic!("unexpected result for {:?}: {:?}", input, x),
+        }
+    }
+
+    #[test]
+    fn it_parses_plain_senders() {
+        expect_plain("user@example.com", "user", "example.com");
+        expect_plain("<user@example.com>", "user", "example.com");
+        expect_plain("<@relay.example,@other.example:user@example.com>", "user", "example.com");
+        expect_plain("odd@local@example.com", "odd@local", "example.com");
+        expect_plain("<\"john doe\"@example.com>", "\"john doe\"", "example.com");
+        expect_plain("\"a\\\"@b\"@example.com", "\"a\\\"@b\"", "example.com");
+        expect_plain("user@[192.0.2.1]", "user", "[192.0.2.1]");
+        expect_plain("user@[IPv6:2001:db8::1]", "user", "[IPv6:2001:db8::1]");
+    }
+
+    #[test]
+    fn it_tells_srs_and_null_senders_apart() {
+        match parse_envelope_sender("<>", Charset::ASCII) {
+            Ok(ForwardableAddress::Null) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+        match parse_envelope_sender("", Charset::ASCII) {
+            Ok(ForwardableAddress::Null) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+        match parse_envelope_sender("<SRS0=HHH=TT=a=user@b>", Charset::ASCII) {
+            Ok(ForwardableAddress::SRS(ref a)) if a.is_0() => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+        // malformed SRS is just a strange local part
+        expect_plain("SRS0=HHH@b", "SRS0=HHH", "b");
+    }
+
+    #[test]
+    fn it_reports_where_senders_are_malformed() {
+        expect_malformed("<user@example.com", Component::Brackets, 17);
+        expect_malformed("<@relay.example>", Component::Route, 1);
+        expect_malformed("user", Component::Domain, 4);
+        expect_malformed("@example.com", Component::Route, 0);
+        expect_malformed("<user@>", Component::Domain, 6);
+        expect_malformed("us er@example.com", Component::Local, 2);
+        expect_malformed("user@exa mple.com", Component::Domain, 8);
+        expect_malformed("\"john doe@example.com", Component::Local, 21);
+        expect_malformed("\"john\"doe@example.com", Component::Local, 6);
+        expect_malformed("<\"john\tdoe\"@example.com>", Component::Local, 1);
+        expect_malformed("user@[192.0.2.256]", Component::Domain, 5);
+        expect_malformed("user@[2001:db8::1]", Component::Domain, 5);
+        expect_malformed("user@[abcd\u{e9}x]", Component::Domain, 5);
+    }
+
+    #[test]
+    fn it_accepts_utf8_only_with_smtputf8() {
+        expect_malformed("j\u{f6}rg@example.com", Component::Local, 1);
+        expect_malformed("\"j\u{f6}rg\"@example.com", Component::Local, 2);
+        expect_malformed("user@b\u{fc}cher.example", Component::Domain, 6);
+        match parse_envelope_sender("<j\u{f6}rg@b\u{fc}cher.example>", Charset::SMTPUTF8) {
+            Ok(ForwardableAddress::Plain{ ref local, ref domain }) if local == "j\u{f6}rg" && domain == "b\u{fc}cher.example" => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+}
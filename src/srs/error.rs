@@ -0,0 +1,190 @@
+/* Errors of the SRS library
+ *
+ * All operations (parsing, forwarding, receiving, timestamps, the database) fail
+ * with SRSError. `code()` is stable across releases and meant for logs and for
+ * mapping to protocol replies, `Display` is for humans and may change.
+ */
+
+extern crate openssl;
+
+use self::openssl::error::ErrorStack;
+
+use srs::transformers::MIN_HASH_LENGTH;
+
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Part of an SRS address
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Component {
+    Separator,
+    Hash,
+    Timestamp,
+    Hostname,
+    Local,
+    OpaqueLocal,
+    Domain,
+    /// Of an envelope sender
+    Brackets,
+    /// Of an envelope sender, like `@relay.example:`
+    Route,
+}
+
+impl fmt::Display for Component {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Component::Separator   => "separator",
+            Component::Hash        => "hash",
+            Component::Timestamp   => "timestamp",
+            Component::Hostname    => "hostname",
+            Component::Local       => "local part",
+            Component::OpaqueLocal => "opaque local part",
+            Component::Domain      => "domain",
+            Component::Brackets    => "angle brackets",
+            Component::Route       => "source route",
+        };
+        return f.write_str(name);
+    }
+}
+
+#[derive(Debug)]
+pub enum SRSError {
+    /// The address does not start with an SRS prefix
+    NotSRS,
+    /// `component` is missing or invalid, starting at byte `offset` of the input
+    Malformed{ component: Component, offset: usize },
+
+    InvalidHostname,
+    InvalidSeparator,
+    NoSecrets,
+
+    /// Carries the hash the newest secret makes for the address
+    HashMismatch{ expected: String },
+    HashTooShort{ len: usize, min: usize },
+    /// A configured hash length below `MIN_HASH_LENGTH`
+    InvalidHashLength(usize),
+    TimestampMalformed,
+    /// Age in days, or hours for `HourlyTimestamper`, negative for timestamps from the future
+    TimestampExpired{ age: i32 },
+    /// An SRS1 address, which the receiver's `SRSScheme` never issues
+    SchemeMismatch,
+    /// An SRS1 address claiming to be ours whose hash does not verify, see `Forwarder::strict`
+    ForgedSRS1,
+
+    /// The local part would be this long, see `OverflowStrategy`
+    LocalPartTooLong(usize),
+    /// A sender the database cannot store
+    InvalidSender,
+    /// A valid sender with a quoted local part like `"john doe"`, which is never rewritten
+    QuotedLocalPart,
+    /// The null sender `<>` is never rewritten
+    NullSender,
+    NotADatabaseAddress,
+    UnknownId,
+
+    HashingError(ErrorStack),
+    RandomError(ErrorStack),
+    StoreError(io::Error),
+}
+
+impl SRSError {
+
+    pub fn code(&self) -> &'static str {
+        match *self {
+            SRSError::NotSRS                 => "not-srs",
+            SRSError::Malformed{..}          => "malformed",
+            SRSError::InvalidHostname        => "invalid-hostname",
+            SRSError::InvalidSeparator       => "invalid-separator",
+            SRSError::NoSecrets              => "no-secrets",
+            SRSError::HashMismatch{..}       => "hash-mismatch",
+            SRSError::HashTooShort{..}       => "hash-too-short",
+            SRSError::InvalidHashLength(_)   => "invalid-hash-length",
+            SRSError::TimestampMalformed     => "timestamp-malformed",
+            SRSError::TimestampExpired{..}   => "timestamp-expired",
+            SRSError::SchemeMismatch         => "scheme-mismatch",
+            SRSError::ForgedSRS1             => "forged-srs1",
+            SRSError::LocalPartTooLong(_)    => "local-part-too-long",
+            SRSError::InvalidSender          => "invalid-sender",
+            SRSError::QuotedLocalPart        => "quoted-local-part",
+            SRSError::NullSender             => "null-sender",
+            SRSError::NotADatabaseAddress    => "not-database-address",
+            SRSError::UnknownId              => "unknown-id",
+            SRSError::HashingError(_)        => "hashing-error",
+            SRSError::RandomError(_)         => "random-error",
+            SRSError::StoreError(_)          => "store-error",
+        }
+    }
+
+    /// Whether retrying the same operation later may succeed
+    pub fn is_temporary(&self) -> bool {
+        match *self {
+            SRSError::HashingError(_) | SRSError::RandomError(_) | SRSError::StoreError(_) => true,
+            _ => false,
+        }
+    }
+
+}
+
+impl fmt::Display for SRSError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SRSError::NotSRS                           => write!(f, "not an SRS address"),
+            SRSError::Malformed{ component, offset }   => write!(f, "malformed {} at byte {}", component, offset),
+            SRSError::InvalidHostname                  => write!(f, "invalid hostname"),
+            SRSError::InvalidSeparator                 => write!(f, "invalid SRS separator, must be one of =, + or -"),
+            SRSError::NoSecrets                        => write!(f, "no secrets"),
+            SRSError::HashMismatch{..}                 => write!(f, "hash verification failed"),
+            SRSError::HashTooShort{ len, min }         => write!(f, "hash too short ({} chars, at least {})", len, min),
+            SRSError::InvalidHashLength(len)           => write!(f, "hash length {} is below the minimum of {}", len, MIN_HASH_LENGTH),
+            SRSError::TimestampMalformed               => write!(f, "malformed timestamp"),
+            SRSError::TimestampExpired{ age }          => write!(f, "timestamp out of range (age {})", age),
+            SRSError::SchemeMismatch                   => write!(f, "SRS1 not issued by this SRS scheme"),
+            SRSError::ForgedSRS1                       => write!(f, "forged SRS1 address"),
+            SRSError::LocalPartTooLong(len)            => write!(f, "local part too long ({} octets)", len),
+            SRSError::InvalidSender                    => write!(f, "invalid sender"),
+            SRSError::QuotedLocalPart                  => write!(f, "quoted local parts are not rewritten"),
+            SRSError::NullSender                       => write!(f, "null sender"),
+            SRSError::NotADatabaseAddress              => write!(f, "not a database address"),
+            SRSError::UnknownId                        => write!(f, "unknown database id"),
+            SRSError::HashingError(ref es)             => write!(f, "hashing error: {}", es),
+            SRSError::RandomError(ref es)              => write!(f, "random number error: {}", es),
+            SRSError::StoreError(ref e)                => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl error::Error for SRSError {
+
+    fn description(&self) -> &str {
+        return self.code();
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            SRSError::HashingError(ref es) | SRSError::RandomError(ref es) => Some(es),
+            SRSError::StoreError(ref e)                                    => Some(e),
+            _ => None,
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::io;
+
+    use super::{Component,SRSError};
+
+    #[test]
+    fn it_describes_errors() {
+        let e = SRSError::Malformed{ component: Component::Timestamp, offset: 10 };
+        assert!(e.code() == "malformed");
+        assert!(e.to_string() == "malformed timestamp at byte 10");
+        assert!(!e.is_temporary());
+        assert!(SRSError::TimestampExpired{ age: 30 }.to_string() == "timestamp out of range (age 30)");
+        assert!(SRSError::StoreError(io::Error::new(io::ErrorKind::Other, "x")).is_temporary());
+    }
+
+}
@@ -1,7 +1,21 @@
+pub mod error;
+
 pub mod parser;
 mod parser_tests;
 
+pub mod clock;
+
 pub mod transformers;
 mod transformer_tests;
 
+pub mod envelope;
+
+pub mod policy;
+
+pub mod syntax;
+
+pub mod secrets;
+
+pub mod database;
+
 mod util;
@@ -1,6 +1,11 @@
 
 use self::SRSAddress::*;
 
+use srs::error::{SRSError,Component};
+use srs::transformers::is_valid_srs_separator;
+
+use std::fmt;
+
 #[derive(Debug)]
 pub enum SRSAddress {
     SRS0(SRS0Address),
@@ -28,10 +33,45 @@ impl SRSAddress {
             _         => panic!("not an SRS1"),
         }
     }
+    /// The local part the address is written with
+    pub fn local_part(&self) -> String {
+        match self {
+            &SRS0(ref a) => format!("{}{}{}{}{}{}{}{}{}",
+                                    a.prefix, a.separator,
+                                    a.hash, a.separator,
+                                    a.tt, a.separator,
+                                    a.hostname, a.separator,
+                                    a.local),
+            &SRS1(ref a) => format!("{}{}{}{}{}{}{}",
+                                    a.prefix, a.separator,
+                                    a.hash, a.separator,
+                                    a.hostname, a.separator,
+                                    a.opaque_local),
+        }
+    }
+    /// Length in octets of `local_part`
+    pub fn local_part_len(&self) -> usize {
+        return self.local_part().len();
+    }
+    /// Domain part the address is written with
+    pub fn domain(&self) -> &str {
+        match self {
+            &SRS0(ref a) => &a.domain,
+            &SRS1(ref a) => &a.domain,
+        }
+    }
+    pub fn borrowed(&self) -> SRSAddressRef {
+        match self {
+            &SRS0(ref a) => SRSAddressRef::SRS0(a.borrowed()),
+            &SRS1(ref a) => SRSAddressRef::SRS1(a.borrowed()),
+        }
+    }
 }
 
+/// Addresses made by the forwarder have the prefix `SRS0`, parsed ones keep it as written
 #[derive(Debug,Clone)]
 pub struct SRS0Address {
+    pub prefix: String,
     pub hash: String,
     pub tt: String,
     pub hostname: String,
@@ -42,6 +82,7 @@ pub struct SRS0Address {
 
 #[derive(Debug,Clone)]
 pub struct SRS1Address {
+    pub prefix: String,
     pub hash: String,
     pub hostname: String,
     pub opaque_local: String,
@@ -49,140 +90,326 @@ pub struct SRS1Address {
     pub separator: String,
 }
 
-struct SRSParser<'a> {
-    input: &'a str,
+impl SRS0Address {
+    pub fn borrowed(&self) -> SRS0AddressRef {
+        return SRS0AddressRef{
+            prefix: &self.prefix,
+            hash: &self.hash,
+            tt: &self.tt,
+            hostname: &self.hostname,
+            local: &self.local,
+            domain: &self.domain,
+            separator: &self.separator,
+        };
+    }
 }
 
-#[derive(Clone,Copy,Debug)]
-pub enum Err {
-    SRSPrefixError,
-    SRS0FormatError,
-    SRS1FormatError,
-    EmptyRemainingAddress,
-    NoDomainInAddress,
+impl SRS1Address {
+    pub fn borrowed(&self) -> SRS1AddressRef {
+        return SRS1AddressRef{
+            prefix: &self.prefix,
+            hash: &self.hash,
+            hostname: &self.hostname,
+            opaque_local: &self.opaque_local,
+            domain: &self.domain,
+            separator: &self.separator,
+        };
+    }
 }
 
-type SRSParserResult = Result<SRSAddress, Err>;
+/// An SRS address borrowing its components from the parsed string.
+/// Verifying and reversing an address works on these without allocating.
+#[derive(Debug,Clone,Copy)]
+pub enum SRSAddressRef<'a> {
+    SRS0(SRS0AddressRef<'a>),
+    SRS1(SRS1AddressRef<'a>),
+}
 
-impl<'a> SRSParser<'a> {
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct SRS0AddressRef<'a> {
+    pub prefix: &'a str,
+    pub hash: &'a str,
+    pub tt: &'a str,
+    pub hostname: &'a str,
+    pub local: &'a str,
+    pub domain: &'a str,
+    pub separator: &'a str,
+}
 
-    fn new(input: &str) -> SRSParser {
-        return SRSParser{
-            input: input,
-        };
-    }
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct SRS1AddressRef<'a> {
+    pub prefix: &'a str,
+    pub hash: &'a str,
+    pub hostname: &'a str,
+    pub opaque_local: &'a str,
+    pub domain: &'a str,
+    pub separator: &'a str,
+}
 
-    fn parse_srs0(&mut self, separator: &'a str) -> SRSParserResult {
+impl<'a> SRSAddressRef<'a> {
 
-        use self::Err::*;
+    /// Parse an ASCII address, see `parse` for SMTPUTF8
+    pub fn from_string(s: &'a str) -> SRSParserResult<'a> {
+        return Self::parse(s, Charset::ASCII);
+    }
 
-        let mut idx = 5;
+    pub fn parse(s: &'a str, charset: Charset) -> SRSParserResult<'a> {
+        let mut p = SRSParser::new(s, charset);
+        return p.parse();
+    }
 
-        let mut comps = Vec::with_capacity(3);
-        for _ in 0..3 {
-            if let Some(pos) = self.input[idx..].find(separator) {
-                comps.push(&self.input[idx..idx+pos]);
-                idx += pos + 1; // Skip it
-            } else {
-                return Err(SRS0FormatError);
-            }
+    pub fn is_0(&self) -> bool {
+        match *self {
+            SRSAddressRef::SRS0(_) => true,
+            SRSAddressRef::SRS1(_) => false,
         }
-        assert!(comps.len() == 3);
+    }
+    pub fn is_1(&self) -> bool { return !self.is_0(); }
 
-        let hash = comps[0];
-        let tt = comps[1];
-        let hostname = comps[2];
+    pub fn hash(&self) -> &'a str {
+        match *self {
+            SRSAddressRef::SRS0(a) => a.hash,
+            SRSAddressRef::SRS1(a) => a.hash,
+        }
+    }
 
-        if idx >= self.input.len() {
-            return Err(EmptyRemainingAddress);
+    pub fn into_owned(self) -> SRSAddress {
+        match self {
+            SRSAddressRef::SRS0(a) => SRS0(a.into_owned()),
+            SRSAddressRef::SRS1(a) => SRS1(a.into_owned()),
         }
+    }
+
+}
 
-        let ld_sep_pos = match self.input[idx..].find("@") {
-            Some(x) => x,
-            None    => return Err(NoDomainInAddress),
+impl<'a> SRS0AddressRef<'a> {
+    pub fn into_owned(self) -> SRS0Address {
+        return SRS0Address{
+            prefix: self.prefix.to_string(),
+            hash: self.hash.to_string(),
+            tt: self.tt.to_string(),
+            hostname: self.hostname.to_string(),
+            local: self.local.to_string(),
+            domain: self.domain.to_string(),
+            separator: self.separator.to_string(),
         };
+    }
+}
 
-        let local = &self.input[idx..idx+ld_sep_pos];
-        let domain = &self.input[idx+ld_sep_pos+1..];
-
-        // TODO could go with zero-copy
-        return Ok(SRS0(SRS0Address{
-            separator: separator.to_string(),
-            hash: hash.to_string(),
-            tt: tt.to_string(),
-            hostname: hostname.to_string(),
-            local: local.to_string(),
-            domain: domain.to_string(),
-        }));
+impl<'a> SRS1AddressRef<'a> {
+    pub fn into_owned(self) -> SRS1Address {
+        return SRS1Address{
+            prefix: self.prefix.to_string(),
+            hash: self.hash.to_string(),
+            hostname: self.hostname.to_string(),
+            opaque_local: self.opaque_local.to_string(),
+            domain: self.domain.to_string(),
+            separator: self.separator.to_string(),
+        };
     }
+}
 
-    fn parse_srs1(&mut self, separator: &'a str) -> SRSParserResult {
+/// The address as written, using the stored prefix and separators
+impl<'a> fmt::Display for SRS0AddressRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}{}{}{}{}{}{}{}{}@{}",
+                      self.prefix, self.separator,
+                      self.hash, self.separator,
+                      self.tt, self.separator,
+                      self.hostname, self.separator,
+                      self.local, self.domain);
+    }
+}
 
-        use self::Err::*;
+impl<'a> fmt::Display for SRS1AddressRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{}{}{}{}{}{}{}@{}",
+                      self.prefix, self.separator,
+                      self.hash, self.separator,
+                      self.hostname, self.separator,
+                      self.opaque_local, self.domain);
+    }
+}
 
-        let mut idx = 5;
+impl<'a> fmt::Display for SRSAddressRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SRSAddressRef::SRS0(ref a) => a.fmt(f),
+            SRSAddressRef::SRS1(ref a) => a.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for SRS0Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return self.borrowed().fmt(f);
+    }
+}
+
+impl fmt::Display for SRS1Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return self.borrowed().fmt(f);
+    }
+}
+
+impl fmt::Display for SRSAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return self.borrowed().fmt(f);
+    }
+}
+
+/// Characters accepted by the parser
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Charset {
+    /// RFC 5321, non-ASCII input is rejected
+    ASCII,
+    /// RFC 6531, UTF-8 is accepted in all components but the hash and timestamp
+    SMTPUTF8,
+}
 
-        let mut comps = Vec::with_capacity(2);
+fn malformed(component: Component, offset: usize) -> SRSError {
+    return SRSError::Malformed{ component: component, offset: offset };
+}
+
+/* The parser never panics: all indices it slices at are the end of the input or
+ * positions right before or after one of the ASCII separators, prefix chars or `@`,
+ * which are always char boundaries.
+ */
+struct SRSParser<'a> {
+    input: &'a str,
+    charset: Charset,
+}
+
+type SRSParserResult<'a> = Result<SRSAddressRef<'a>, SRSError>;
 
-        for _ in 0..2 {
-            if let Some(pos) = self.input[idx..].find(separator) {
-                comps.push(&self.input[idx..idx+pos]);
-                idx += pos + 1; // Skip separator
-            } else {
-                return Err(SRS1FormatError);
-            }
+impl<'a> SRSParser<'a> {
+
+    fn new(input: &'a str, charset: Charset) -> SRSParser<'a> {
+        return SRSParser{
+            input: input,
+            charset: charset,
+        };
+    }
+
+    /// `value` starts at byte `start` of the input
+    fn check_charset(&self, component: Component, start: usize, value: &str) -> Result<(), SRSError> {
+        let ascii_only = match component {
+            Component::Hash | Component::Timestamp => true,
+            _                                      => self.charset == Charset::ASCII,
+        };
+        match value.bytes().position(|b| b >= 0x80) {
+            Some(pos) if ascii_only => return Err(malformed(component, start + pos)),
+            _                       => return Ok(()),
         }
-        assert!(comps.len() == 2);
+    }
 
-        let hash = comps[0];
-        let hostname = comps[1];
+    /// The component up to the next separator, `idx` is advanced past the separator
+    fn next_component(&self, idx: &mut usize, separator: &str, component: Component) -> Result<&'a str, SRSError> {
+        let start = *idx;
+        let pos = match self.input[start..].find(separator) {
+            Some(pos) => pos,
+            None      => return Err(malformed(component, start)),
+        };
+        let value = &self.input[start..start+pos];
+        try!(self.check_charset(component, start, value));
+        *idx = start + pos + separator.len();
+        return Ok(value);
+    }
 
-        let ld_sep_pos = match self.input[idx..].find("@") {
-            Some(x) => x,
-            None    => return Err(NoDomainInAddress),
+    /// The local part up to the first `@` from `idx`, and the domain after it
+    fn local_and_domain(&self, idx: usize, local_component: Component) -> Result<(&'a str, &'a str), SRSError> {
+        let at = match self.input[idx..].find('@') {
+            Some(pos) => idx + pos,
+            None      => return Err(malformed(Component::Domain, self.input.len())),
         };
+        let local = &self.input[idx..at];
+        let domain = &self.input[at+1..];
+        try!(self.check_charset(local_component, idx, local));
+        try!(self.check_charset(Component::Domain, at + 1, domain));
+        return Ok((local, domain));
+    }
+
+    fn parse_srs0(&mut self, prefix: &'a str, separator: &'a str) -> SRSParserResult<'a> {
+
+        let mut idx = prefix.len() + separator.len();
+
+        let hash = try!(self.next_component(&mut idx, separator, Component::Hash));
+        let tt = try!(self.next_component(&mut idx, separator, Component::Timestamp));
+        let hostname = try!(self.next_component(&mut idx, separator, Component::Hostname));
+
+        if idx >= self.input.len() {
+            return Err(malformed(Component::Local, idx));
+        }
 
-        let opaque_local = &self.input[idx..idx+ld_sep_pos];
-        let domain = &self.input[idx+ld_sep_pos+1..];
+        let (local, domain) = try!(self.local_and_domain(idx, Component::Local));
 
-        // TODO do zero-copy here
-        return Ok (SRS1(SRS1Address{
-            separator: separator.to_string(),
-            hash: hash.to_string(),
-            hostname: hostname.to_string(),
-            opaque_local: opaque_local.to_string(),
-            domain: domain.to_string(),
+        return Ok(SRSAddressRef::SRS0(SRS0AddressRef{
+            prefix: prefix,
+            separator: separator,
+            hash: hash,
+            tt: tt,
+            hostname: hostname,
+            local: local,
+            domain: domain,
+        }));
+    }
+
+    fn parse_srs1(&mut self, prefix: &'a str, separator: &'a str) -> SRSParserResult<'a> {
+
+        let mut idx = prefix.len() + separator.len();
+
+        let hash = try!(self.next_component(&mut idx, separator, Component::Hash));
+        let hostname = try!(self.next_component(&mut idx, separator, Component::Hostname));
+
+        let (opaque_local, domain) = try!(self.local_and_domain(idx, Component::OpaqueLocal));
+
+        return Ok(SRSAddressRef::SRS1(SRS1AddressRef{
+            prefix: prefix,
+            separator: separator,
+            hash: hash,
+            hostname: hostname,
+            opaque_local: opaque_local,
+            domain: domain,
         }));
 
     }
 
-    fn parse(&mut self) -> SRSParserResult {
+    fn parse(&mut self) -> SRSParserResult<'a> {
 
-        if self.input.len() < 5 {
-            return Err(Err::SRSPrefixError);
+        let bytes = self.input.as_bytes();
+        if bytes.len() < 5 {
+            return Err(SRSError::NotSRS);
         }
 
-        let version = match &self.input[0..4] {
-            "SRS0" => 0,
-            "SRS1" => 1,
-            _      => return Err(Err::SRSPrefixError),
+        // Any case, like libsrs2, for MTAs that change the case of addresses
+        let is_srs1 = match &bytes[0..4] {
+            p if p.eq_ignore_ascii_case(b"SRS0") => false,
+            p if p.eq_ignore_ascii_case(b"SRS1") => true,
+            _                                    => return Err(SRSError::NotSRS),
         };
+        let prefix = &self.input[0..4];
 
+        if !self.input.is_char_boundary(5) || !is_valid_srs_separator(&self.input[4..5]) {
+            return Err(malformed(Component::Separator, 4));
+        }
         let separator = &self.input[4..5];
 
-        return match version {
-            0 => self.parse_srs0(separator),
-            1 => self.parse_srs1(separator),
-            _ => panic!("variable should not contain a value != 0 or 1"),
+        return match is_srs1 {
+            false => self.parse_srs0(prefix, separator),
+            true  => self.parse_srs1(prefix, separator),
         };
     }
 }
 
 impl SRSAddress {
 
-    pub fn from_string(s: &str) -> SRSParserResult {
-        let mut p = SRSParser::new(s);
-        return p.parse();
+    /// Parse an ASCII address, see `parse` for SMTPUTF8
+    pub fn from_string(s: &str) -> Result<SRSAddress, SRSError> {
+        return Self::parse(s, Charset::ASCII);
+    }
+
+    pub fn parse(s: &str, charset: Charset) -> Result<SRSAddress, SRSError> {
+        return SRSAddressRef::parse(s, charset).map(SRSAddressRef::into_owned);
     }
 
 }
@@ -1,7 +1,8 @@
 #[cfg(test)]
 mod parser_tests {
 
-    use srs::parser::SRSAddress;
+    use srs::error::{SRSError,Component};
+    use srs::parser::{SRSAddress,SRSAddressRef,SRS0Address,SRS1Address,Charset};
 
     //fn new_parser_result(s: &str) -> SRSParserResult {
     //}
@@ -102,5 +103,186 @@ mod parser_tests {
         assert!(r.is_err());
     }
 
-}
+    #[test]
+    fn it_reports_the_malformed_component_and_offset() {
+        let cases = vec![
+            ("SRS0=HHH",                 Component::Hash,      5),
+            ("SRS0=HHH=TT",              Component::Timestamp, 9),
+            ("SRS0=HHH=TT=source.com",   Component::Hostname,  12),
+            ("SRS0=HHH=TT=source.com=",  Component::Local,     23),
+            ("SRS0=HHH=TT=source.com=u", Component::Domain,    24),
+            ("SRS1=GGG=orig.hostname",   Component::Hostname,  9),
+        ];
+        for (a, component, offset) in cases {
+            match SRSAddress::from_string(a) {
+                Err(SRSError::Malformed{ component: c, offset: o }) if c == component && o == offset => (),
+                x => panic!("unexpected result for {:?}: {:?}", a, x),
+            }
+        }
+        match SRSAddress::from_string("user@domain") {
+            Err(SRSError::NotSRS) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn it_parses_borrowed_addresses() {
+        let input = "SRS0=HHH=TT=source.com=user@forwarder";
+        let r = SRSAddressRef::from_string(input).expect("should parse");
+        let a = match r {
+            SRSAddressRef::SRS0(a) => a,
+            x => panic!("unexpected result {:?}", x),
+        };
+        assert!(a.hash == "HHH" && a.tt == "TT" && a.hostname == "source.com");
+        assert!(a.local == "user" && a.domain == "forwarder" && a.separator == "=");
+        // components point into the input
+        assert!(a.local.as_ptr() == input[23..].as_ptr());
+
+        let owned = r.into_owned();
+        assert!(owned.local_part() == "SRS0=HHH=TT=source.com=user");
+        match owned.borrowed() {
+            SRSAddressRef::SRS0(b) => assert!(a == b),
+            x => panic!("unexpected result {:?}", x),
+        }
+
+        let r = SRSAddressRef::from_string("SRS1=GGG=orig.hostname==HHH=TT=a=u@d").expect("should parse");
+        assert!(r.is_1() && r.hash() == "GGG");
+    }
+
+    /// xorshift, good enough to generate test inputs reproducibly
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            return (self.0 % n as u64) as usize;
+        }
+        fn string(&mut self, alphabet: &[&str], max_len: usize) -> String {
+            let len = self.below(max_len + 1);
+            return (0..len).map(|_| alphabet[self.below(alphabet.len())]).collect();
+        }
+    }
+
+    const SEPARATORS: [&str; 3] = ["=", "+", "-"];
+    const PREFIXES: [&str; 4] = ["SRS0", "srs0", "Srs0", "sRS0"];
+    const ALPHABET: [&str; 11] = ["a", "Z", "0", ".", "_", "=", "+", "-", "@", "\u{e9}", "\u{1f600}"];
+
+    fn without(alphabet: &[&'static str], excluded: &[&str]) -> Vec<&'static str> {
+        return alphabet.iter().cloned().filter(|c| !excluded.contains(c)).collect();
+    }
+
+    #[test]
+    fn it_round_trips_everything_it_parses() {
+        let mut rng = Rng(0x5eed);
+        let mut parsed = 0;
+        for _ in 0..20000 {
+            let input = format!("{}{}{}", PREFIXES[rng.below(4)].replace("0", &rng.below(2).to_string()),
+                                SEPARATORS[rng.below(3)], rng.string(&ALPHABET, 30));
+            if let Ok(a) = SRSAddress::parse(&input, Charset::SMTPUTF8) {
+                assert!(a.to_string() == input, "{:?} became {:?}", input, a.to_string());
+                parsed += 1;
+            }
+        }
+        assert!(parsed > 1000, "only {} parsed", parsed);
+    }
 
+    #[test]
+    fn it_parses_what_it_serializes() {
+        let mut rng = Rng(0xadd7e55);
+        for _ in 0..1000 {
+            let separator = SEPARATORS[rng.below(3)];
+            let component = without(&ALPHABET, &[separator]);
+            let hash = without(&component, &["\u{e9}", "\u{1f600}"]);
+            let local = without(&ALPHABET, &["@"]);
+
+            let mut srs0 = SRS0Address{
+                prefix: PREFIXES[rng.below(4)].to_string(),
+                hash: rng.string(&hash, 8),
+                tt: rng.string(&hash, 3),
+                hostname: rng.string(&component, 12),
+                local: rng.string(&local, 12),
+                domain: rng.string(&ALPHABET, 12),
+                separator: separator.to_string(),
+            };
+            if srs0.local.is_empty() {
+                srs0.local.push_str("u");
+            }
+            let a = SRSAddress::parse(&srs0.to_string(), Charset::SMTPUTF8).expect("should parse").srs0();
+            assert!(a.borrowed() == srs0.borrowed(), "{:?} became {:?}", srs0, a);
+
+            let srs1 = SRS1Address{
+                prefix: PREFIXES[rng.below(4)].replace("0", "1"),
+                hash: rng.string(&hash, 8),
+                hostname: rng.string(&component, 12),
+                opaque_local: rng.string(&local, 30),
+                domain: rng.string(&ALPHABET, 12),
+                separator: separator.to_string(),
+            };
+            let a = SRSAddress::parse(&srs1.to_string(), Charset::SMTPUTF8).expect("should parse").srs1();
+            assert!(a.borrowed() == srs1.borrowed(), "{:?} became {:?}", srs1, a);
+        }
+    }
+
+    #[test]
+    fn it_accepts_any_case_for_the_prefix() {
+        for &(input, prefix) in &[("srs0=HHH=TT=source.com=user@forwarder", "srs0"), ("Srs1+GGG+orig.hostname+=HHH=TT=a=u@d", "Srs1")] {
+            let a = SRSAddress::from_string(input).expect("should parse");
+            match a {
+                SRSAddress::SRS0(ref a) => assert!(a.prefix == prefix && a.local == "user"),
+                SRSAddress::SRS1(ref a) => assert!(a.prefix == prefix && a.separator == "+"),
+            }
+            assert!(a.to_string() == input);
+        }
+    }
+
+    #[test]
+    fn it_rejects_invalid_separators() {
+        for input in &["SRS0xHHHxTTxsource.comxuser@forwarder", "SRS0.a.b.c.d@e", "srs1\u{e9}a@b", "SRS0 a@b"] {
+            match SRSAddress::from_string(input) {
+                Err(SRSError::Malformed{ component: Component::Separator, offset: 4 }) => (),
+                x => panic!("unexpected result for {:?}: {:?}", input, x),
+            }
+        }
+        match SRSAddress::from_string("SRS2=HHH=TT=source.com=user@forwarder") {
+            Err(SRSError::NotSRS) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn it_does_not_panic_on_multibyte_input() {
+        let valid = "SRS1=GGG=orig.hostname==HHH=TT=a=user@domain";
+        for c in &["\u{e9}", "\u{20ac}", "\u{1f600}"] {
+            for pos in 0..valid.len()+1 {
+                let input = format!("{}{}{}", &valid[..pos], c, &valid[pos..]);
+                for end in (0..input.len()+1).filter(|&end| input.is_char_boundary(end)) {
+                    let _ = SRSAddress::parse(&input[..end], Charset::ASCII);
+                    let _ = SRSAddress::parse(&input[..end], Charset::SMTPUTF8);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn it_accepts_utf8_only_with_smtputf8() {
+        let input = "SRS0=HHH=TT=b\u{fc}cher.example=j\u{f6}rg@forwarder";
+        match SRSAddress::from_string(input) {
+            Err(SRSError::Malformed{ component: Component::Hostname, offset: 13 }) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+        let a = SRSAddress::parse(input, Charset::SMTPUTF8).expect("should parse").srs0();
+        assert!(a.hostname == "b\u{fc}cher.example" && a.local == "j\u{f6}rg");
+        // hashes and timestamps are always ASCII
+        match SRSAddress::parse("SRS0=HHH=T\u{e9}=a=u@b", Charset::SMTPUTF8) {
+            Err(SRSError::Malformed{ component: Component::Timestamp, offset: 10 }) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+        match SRSAddress::from_string("SRS1=GGG=a==HHH=TT=b=j\u{f6}rg@forwarder") {
+            Err(SRSError::Malformed{ component: Component::OpaqueLocal, offset: 22 }) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+}
@@ -0,0 +1,174 @@
+/* Forwarding policy
+ *
+ * Decides whether a sender is rewritten at all and under which SRS domain,
+ * before the Forwarder is asked. Rules are checked in order and the first
+ * match decides, senders no rule matches are rewritten under the forwarder's
+ * own domain.
+ */
+
+use std::ascii::AsciiExt;
+
+use srs::transformers::ForwardableAddress;
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum Matcher {
+    /// The null sender `<>`, which has nothing to rewrite
+    NullSender,
+    /// `postmaster` in any case, RFC 5321 4.5.1
+    Postmaster,
+    /// Senders from one of these domains, `.example.com` matches all subdomains instead
+    SenderDomain(Vec<String>),
+    /// Mail to one of these domains, same syntax as `SenderDomain`
+    RecipientDomain(Vec<String>),
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum Action {
+    /// Leave the sender alone
+    Skip,
+    /// Rewrite under this SRS domain instead of the forwarder's
+    RewriteAs(String),
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct Rule {
+    /// Reported with the decision, e.g. `local_domains`
+    pub name: String,
+    pub matcher: Matcher,
+    pub action: Action,
+}
+
+/// Name reported if no rule matched
+pub const DEFAULT_RULE: &'static str = "default";
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Decision<'a> {
+    Skip{ rule: &'a str },
+    /// `srs_domain` is `None` for the forwarder's own domain
+    Rewrite{ srs_domain: Option<&'a str>, rule: &'a str },
+}
+
+impl<'a> Decision<'a> {
+    /// Name of the rule that matched
+    pub fn rule(&self) -> &'a str {
+        match *self {
+            Decision::Skip{ rule } | Decision::Rewrite{ rule, .. } => rule,
+        }
+    }
+}
+
+/// Case-insensitive, a leading dot (`.example.com`) matches all subdomains instead
+pub fn domain_matches(domain: &str, patterns: &[String]) -> bool {
+    let domain = domain.to_ascii_lowercase();
+    return patterns.iter().any(|p| {
+        let p = p.to_ascii_lowercase();
+        match p.starts_with(".") {
+            true  => domain.ends_with(&p),
+            false => domain == p,
+        }
+    });
+}
+
+pub struct Policy {
+    /// Checked in order, the first match decides
+    pub rules: Vec<Rule>,
+}
+
+impl Policy {
+
+    /// Skips the null sender and postmaster, rewrites everything else
+    pub fn new() -> Policy {
+        return Policy{
+            rules: vec![
+                Rule{ name: "null_sender".to_string(), matcher: Matcher::NullSender, action: Action::Skip },
+                Rule{ name: "postmaster".to_string(), matcher: Matcher::Postmaster, action: Action::Skip },
+            ],
+        };
+    }
+
+    /// `recipient_domain` is `None` if the caller does not know it, rules
+    /// matching recipients are skipped then.
+    pub fn decide<'a>(&'a self, sender: &ForwardableAddress, recipient_domain: Option<&str>) -> Decision<'a> {
+
+        // SRS senders are matched by the host that forwarded them to us
+        let (local, domain) = match *sender {
+            ForwardableAddress::Plain{ ref local, ref domain } => (Some(local.to_string()), Some(domain.as_str())),
+            ForwardableAddress::SRS(ref a)                     => (Some(a.local_part()), Some(a.domain())),
+            ForwardableAddress::Null                           => (None, None),
+        };
+
+        for rule in &self.rules {
+            let matches = match rule.matcher {
+                Matcher::NullSender                   => local.is_none(),
+                Matcher::Postmaster                   => local.as_ref().map_or(false, |l| l.eq_ignore_ascii_case("postmaster")),
+                Matcher::SenderDomain(ref domains)    => domain.map_or(false, |d| domain_matches(d, domains)),
+                Matcher::RecipientDomain(ref domains) => recipient_domain.map_or(false, |d| domain_matches(d, domains)),
+            };
+            if matches {
+                return match rule.action {
+                    Action::Skip                      => Decision::Skip{ rule: &rule.name },
+                    Action::RewriteAs(ref srs_domain) => Decision::Rewrite{ srs_domain: Some(srs_domain), rule: &rule.name },
+                };
+            }
+        }
+
+        return Decision::Rewrite{ srs_domain: None, rule: DEFAULT_RULE };
+    }
+
+}
+
+#[cfg(test)]
+mod test {
+
+    use srs::parser::SRSAddress;
+    use srs::transformers::ForwardableAddress;
+    use super::{Policy,Rule,Matcher,Action,Decision,DEFAULT_RULE};
+
+    fn plain(local: &str, domain: &str) -> ForwardableAddress {
+        return ForwardableAddress::Plain{ local: local.to_string(), domain: domain.to_string() };
+    }
+
+    fn make_policy() -> Policy {
+        let mut p = Policy::new();
+        p.rules.push(Rule{
+            name: "local_domains".to_string(),
+            matcher: Matcher::SenderDomain(vec!["example.com".to_string(), ".example.org".to_string()]),
+            action: Action::Skip,
+        });
+        p.rules.push(Rule{
+            name: "srs_domains".to_string(),
+            matcher: Matcher::RecipientDomain(vec!["lists.example.net".to_string()]),
+            action: Action::RewriteAs("srs.lists.example.net".to_string()),
+        });
+        return p;
+    }
+
+    #[test]
+    fn it_reports_the_rule_that_matched() {
+        let p = make_policy();
+        assert!(p.decide(&ForwardableAddress::Null, None) == Decision::Skip{ rule: "null_sender" });
+        assert!(p.decide(&plain("PostMaster", "a"), None) == Decision::Skip{ rule: "postmaster" });
+        assert!(p.decide(&plain("user", "EXAMPLE.com"), None) == Decision::Skip{ rule: "local_domains" });
+        assert!(p.decide(&plain("user", "mail.example.org"), None).rule() == "local_domains");
+        assert!(p.decide(&plain("user", "example.org"), None) == Decision::Rewrite{ srs_domain: None, rule: DEFAULT_RULE });
+        assert!(p.decide(&plain("postmaster.bot", "a"), None).rule() == DEFAULT_RULE);
+    }
+
+    #[test]
+    fn it_picks_the_srs_domain_by_recipient() {
+        let p = make_policy();
+        assert!(p.decide(&plain("user", "a"), Some("Lists.Example.net"))
+                == Decision::Rewrite{ srs_domain: Some("srs.lists.example.net"), rule: "srs_domains" });
+        assert!(p.decide(&plain("user", "a"), Some("example.net")).rule() == DEFAULT_RULE);
+        // skipping comes first
+        assert!(p.decide(&plain("user", "example.com"), Some("lists.example.net")).rule() == "local_domains");
+    }
+
+    #[test]
+    fn it_matches_srs_senders_by_their_domain() {
+        let p = make_policy();
+        let srs = SRSAddress::from_string("SRS0=HHH=TT=origin=user@example.com").unwrap();
+        assert!(p.decide(&ForwardableAddress::SRS(srs), None).rule() == "local_domains");
+    }
+
+}
@@ -0,0 +1,158 @@
+/* Secrets file in the format used by postsrsd / libsrs2
+ *
+ * One secret per line, empty lines are ignored.
+ * The first secret signs new addresses, all secrets are accepted when verifying,
+ * so a new secret is rolled out by prepending it to the file.
+ */
+
+extern crate openssl;
+
+use self::openssl::hash::MessageDigest;
+
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use srs::error::SRSError;
+use srs::transformers::{Forwarder,Receiver,Timestamper};
+
+#[derive(Debug)]
+pub enum SecretsError {
+    Io(io::Error),
+    WorldReadable,
+    NoSecrets,
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct Secrets {
+    secrets: Vec<Vec<u8>>, // newest first
+}
+
+impl Secrets {
+
+    pub fn parse(content: &[u8]) -> Result<Secrets, SecretsError> {
+        let secrets: Vec<Vec<u8>> = content
+            .split(|&b| b == b'\n')
+            .map(|line| match line.last() {
+                Some(&b'\r') => &line[..line.len()-1],
+                _            => line,
+            })
+            .filter(|line| !line.is_empty())
+            .map(|line| line.to_vec())
+            .collect();
+        if secrets.is_empty() {
+            return Err(SecretsError::NoSecrets);
+        }
+        return Ok(Secrets{ secrets: secrets });
+    }
+
+    /// Load a secrets file, refusing files that any user on the system could read.
+    pub fn load(path: &Path) -> Result<Secrets, SecretsError> {
+        let mut f = try!(fs::File::open(path).map_err(SecretsError::Io));
+        let meta = try!(f.metadata().map_err(SecretsError::Io));
+        if meta.permissions().mode() & 0o004 != 0 {
+            return Err(SecretsError::WorldReadable);
+        }
+        let mut content = Vec::new();
+        try!(f.read_to_end(&mut content).map_err(SecretsError::Io));
+        return Self::parse(&content);
+    }
+
+    /// Append the secrets of `other`, which are then only used for verification.
+    pub fn extend(&mut self, other: Secrets) {
+        self.secrets.extend(other.secrets);
+    }
+
+    pub fn as_slice(&self) -> &[Vec<u8>] {
+        return &self.secrets;
+    }
+
+    pub fn forwarder<T>(&self, hostname: Vec<u8>, md: MessageDigest, separator: &str, timestamper: T) -> Result<Forwarder<T>, SRSError>
+        where T: Timestamper {
+        return Forwarder::with_secrets(self.secrets.clone(), hostname, md, separator, timestamper);
+    }
+
+    pub fn receiver<T>(&self, hostname: Vec<u8>, md: MessageDigest, timestamper: T) -> Result<Receiver<T>, SRSError>
+        where T: Timestamper {
+        return Receiver::with_secrets(self.secrets.clone(), hostname, md, timestamper);
+    }
+
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::process;
+
+    use openssl::hash::MessageDigest;
+
+    use srs::transformers::{ForwardableAddress,SRSTimestamper};
+    use super::{Secrets,SecretsError};
+
+    fn write_secrets_file(name: &str, content: &str, mode: u32) -> PathBuf {
+        let mut p = env::temp_dir();
+        p.push(format!("rustsrsd-test-{}-{}.secret", process::id(), name));
+        fs::write(&p, content).expect("should write secrets file");
+        fs::set_permissions(&p, fs::Permissions::from_mode(mode)).expect("should set mode");
+        return p;
+    }
+
+    #[test]
+    fn it_parses_one_secret_per_line() {
+        let s = Secrets::parse(b"newest\r\n\nolder\noldest").expect("should parse");
+        assert!(s.as_slice() == &[b"newest".to_vec(), b"older".to_vec(), b"oldest".to_vec()]);
+    }
+
+    #[test]
+    fn it_requires_a_secret() {
+        match Secrets::parse(b"\n\r\n") {
+            Err(SecretsError::NoSecrets) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn it_loads_private_files() {
+        let p = write_secrets_file("private", "tops3cr3t\n", 0o600);
+        let s = Secrets::load(&p).expect("should load");
+        assert!(s.as_slice() == &[b"tops3cr3t".to_vec()]);
+        fs::remove_file(&p).unwrap();
+    }
+
+    #[test]
+    fn it_refuses_world_readable_files() {
+        let p = write_secrets_file("public", "tops3cr3t\n", 0o644);
+        match Secrets::load(&p) {
+            Err(SecretsError::WorldReadable) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+        fs::remove_file(&p).unwrap();
+    }
+
+    #[test]
+    fn it_builds_forwarders_and_receivers() {
+        let old = Secrets::parse(b"oldsecret\n").unwrap();
+        let mut rolled = Secrets::parse(b"newsecret\n").unwrap();
+        rolled.extend(old.clone());
+
+        let f_old = old.forwarder("a".to_owned().into_bytes(), MessageDigest::sha512(), "=",
+                                  SRSTimestamper::new(5)).expect("should build forwarder");
+        let r_rolled = rolled.receiver("a".to_owned().into_bytes(), MessageDigest::sha512(),
+                                       SRSTimestamper::new(5)).expect("should build receiver");
+
+        let srs = f_old.forward(ForwardableAddress::Plain{
+            local: "user".to_string(),
+            domain: "origin".to_string(),
+        }).expect("should forward");
+        let (rewritten, key_index) = r_rolled.receive_with_key_index(&srs).expect("old secret should verify");
+        assert!(rewritten == "user@origin");
+        assert!(key_index == 1);
+    }
+
+}
@@ -0,0 +1,234 @@
+/* RFC 5321 address syntax
+ *
+ * Local parts are a dot-atom or a quoted-string, domains are LDH labels where
+ * internationalized labels must be valid IDNA A-labels (xn--...). With
+ * Charset::SMTPUTF8, RFC 6531 additionally allows UTF-8 in local parts and
+ * U-labels in domains.
+ */
+
+use std::net::{Ipv4Addr,Ipv6Addr};
+
+use srs::parser::Charset;
+
+/// RFC 5321 limit on the length of a domain in octets
+pub const MAX_DOMAIN_LEN: usize = 255;
+/// RFC 1035 limit on the length of a label in octets
+pub const MAX_LABEL_LEN: usize = 63;
+
+fn is_atext(c: char, charset: Charset) -> bool {
+    return c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c)
+        || (charset == Charset::SMTPUTF8 && !c.is_ascii() && !c.is_control() && !c.is_whitespace());
+}
+
+/// `atom *("." atom)`, e.g. `first.last`
+pub fn is_dot_atom(s: &str, charset: Charset) -> bool {
+    return s.split('.').all(|atom| !atom.is_empty() && atom.chars().all(|c| is_atext(c, charset)));
+}
+
+/// `DQUOTE *(qtextSMTP / quoted-pairSMTP) DQUOTE`, e.g. `"john doe"`
+pub fn is_quoted_string(s: &str, charset: Charset) -> bool {
+    if s.len() < 2 || !s.starts_with('"') || !s.ends_with('"') {
+        return false;
+    }
+    let is_text = |c: char| match c {
+        ' '...'~' => true,
+        _         => charset == Charset::SMTPUTF8 && !c.is_ascii() && !c.is_control(),
+    };
+    let mut chars = s[1..s.len()-1].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c) if c.is_ascii() && is_text(c) => (),
+                _                                     => return false,
+            },
+            '"' => return false,
+            c if is_text(c) => (),
+            _ => return false,
+        }
+    }
+    return true;
+}
+
+pub fn is_valid_local_part(s: &str, charset: Charset) -> bool {
+    return is_dot_atom(s, charset) || is_quoted_string(s, charset);
+}
+
+/// Decode the RFC 3492 punycode after the `xn--` of an A-label
+fn punycode_decode(input: &str) -> Option<Vec<char>> {
+
+    const BASE: u32 = 36;
+    const TMIN: u32 = 1;
+    const TMAX: u32 = 26;
+
+    fn adapt(delta: u32, numpoints: u32, first: bool) -> u32 {
+        let mut delta = match first {
+            true  => delta / 700,
+            false => delta / 2,
+        };
+        delta += delta / numpoints;
+        let mut k = 0;
+        while delta > ((BASE - TMIN) * TMAX) / 2 {
+            delta /= BASE - TMIN;
+            k += BASE;
+        }
+        return k + (BASE - TMIN + 1) * delta / (delta + 38);
+    }
+
+    // Basic code points come before the last delimiter
+    let (mut output, encoded): (Vec<char>, &str) = match input.rfind('-') {
+        Some(pos) => (input[..pos].chars().collect(), &input[pos+1..]),
+        None      => (Vec::new(), input),
+    };
+    if output.iter().any(|c| !c.is_ascii()) {
+        return None;
+    }
+
+    let mut n: u32 = 128;
+    let mut i: u32 = 0;
+    let mut bias: u32 = 72;
+    let mut digits = encoded.bytes();
+    loop {
+        let old_i = i;
+        let mut w: u32 = 1;
+        let mut k = BASE;
+        let mut first_digit = true;
+        loop {
+            let digit = match digits.next() {
+                Some(b @ b'a'...b'z') => (b - b'a') as u32,
+                Some(b @ b'A'...b'Z') => (b - b'A') as u32,
+                Some(b @ b'0'...b'9') => (b - b'0') as u32 + 26,
+                None if first_digit   => return Some(output),
+                _                     => return None,
+            };
+            first_digit = false;
+            i = match digit.checked_mul(w).and_then(|d| i.checked_add(d)) {
+                Some(i) => i,
+                None    => return None, // overflow
+            };
+            let t = match k {
+                k if k <= bias        => TMIN,
+                k if k >= bias + TMAX => TMAX,
+                k                     => k - bias,
+            };
+            if digit < t {
+                break;
+            }
+            w = match w.checked_mul(BASE - t) {
+                Some(w) => w,
+                None    => return None,
+            };
+            k += BASE;
+        }
+        let len = output.len() as u32 + 1;
+        bias = adapt(i - old_i, len, old_i == 0);
+        n = match n.checked_add(i / len) {
+            Some(n) => n,
+            None    => return None,
+        };
+        i %= len;
+        match ::std::char::from_u32(n) {
+            Some(c) if n >= 128 => output.insert(i as usize, c),
+            _                   => return None,
+        }
+        i += 1;
+    }
+}
+
+/// An A-label must decode to a label that needs encoding
+fn is_a_label(label: &str) -> bool {
+    return match punycode_decode(&label[4..]) {
+        Some(ref decoded) => decoded.iter().any(|c| !c.is_ascii()),
+        None              => false,
+    };
+}
+
+fn is_valid_label(label: &str, charset: Charset) -> bool {
+    if label.is_empty() || label.starts_with('-') || label.ends_with('-') {
+        return false;
+    }
+    if !label.is_ascii() {
+        // U-label, only valid in SMTPUTF8 mode
+        return charset == Charset::SMTPUTF8 && label.chars().all(|c| c.is_alphanumeric() || c == '-');
+    }
+    if label.len() > MAX_LABEL_LEN || !label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+        return false;
+    }
+    // Hyphens in the third and fourth position are reserved for A-labels, RFC 5891 4.2.3.1
+    if label.len() >= 4 && &label[2..4] == "--" {
+        return label[..2].eq_ignore_ascii_case("xn") && is_a_label(label);
+    }
+    return true;
+}
+
+/// A domain name like `example.com` or `xn--bcher-kva.example`, without trailing dot
+pub fn is_valid_domain(s: &str, charset: Charset) -> bool {
+    return s.len() <= MAX_DOMAIN_LEN && s.split('.').all(|label| is_valid_label(label, charset));
+}
+
+/// An address literal like `[192.0.2.1]` or `[IPv6:2001:db8::1]`
+pub fn is_address_literal(domain: &str) -> bool {
+    if !domain.starts_with('[') || !domain.ends_with(']') || domain.len() < 2 {
+        return false;
+    }
+    let literal = &domain[1..domain.len()-1];
+    if literal.get(..5).map_or(false, |p| p.eq_ignore_ascii_case("IPv6:")) {
+        return literal[5..].parse::<Ipv6Addr>().is_ok();
+    }
+    return literal.parse::<Ipv4Addr>().is_ok();
+}
+
+#[cfg(test)]
+mod test {
+
+    use srs::parser::Charset::{ASCII,SMTPUTF8};
+    use super::*;
+
+    #[test]
+    fn it_validates_local_parts() {
+        for valid in &["user", "first.last", "a+tag", "o'neil", "x!#$%&'*+-/=?^_`{|}~", "\"john doe\"", "\"a\\\"b\"", "\"\""] {
+            assert!(is_valid_local_part(valid, ASCII), "{:?} should be valid", valid);
+        }
+        for invalid in &["", ".user", "user.", "a..b", "us er", "a@b", "a\"b", "\"a", "\"a\\\"", "\"\u{7}\"", "j\u{f6}rg", "\"j\u{f6}rg\""] {
+            assert!(!is_valid_local_part(invalid, ASCII), "{:?} should be invalid", invalid);
+        }
+        assert!(is_valid_local_part("j\u{f6}rg", SMTPUTF8));
+        assert!(is_valid_local_part("\"j\u{f6}rg doe\"", SMTPUTF8));
+        assert!(!is_valid_local_part("j\u{f6}rg\u{a0}", SMTPUTF8));
+    }
+
+    #[test]
+    fn it_validates_domains() {
+        for valid in &["a", "example.com", "EXAMPLE.com", "mail-1.example.org", "xn--bcher-kva.example", "XN--mnchen-3ya.de", "1.2.3"] {
+            assert!(is_valid_domain(valid, ASCII), "{:?} should be valid", valid);
+        }
+        let long_label = "a".repeat(MAX_LABEL_LEN + 1);
+        let long_domain = vec!["a"; MAX_DOMAIN_LEN / 2 + 2].join(".");
+        for invalid in &["", ".", "example.com.", "a..b", "-a.b", "a-.b", "a_b.c", "a=b", "a@b", "a b",
+                         "ab--cd.example", "xn--a-.example", "xn--.example", "xn--bcher-kva!.example",
+                         "b\u{fc}cher.example", &long_label, &long_domain] {
+            assert!(!is_valid_domain(invalid, ASCII), "{:?} should be invalid", invalid);
+        }
+        assert!(is_valid_domain("b\u{fc}cher.example", SMTPUTF8));
+        assert!(!is_valid_domain("b\u{fc}cher example", SMTPUTF8));
+    }
+
+    #[test]
+    fn it_decodes_punycode() {
+        assert!(punycode_decode("bcher-kva") == Some("b\u{fc}cher".chars().collect()));
+        assert!(punycode_decode("mnchen-3ya") == Some("m\u{fc}nchen".chars().collect()));
+        assert!(punycode_decode("wgv71a119e") == Some("\u{65e5}\u{672c}\u{8a9e}".chars().collect()));
+        assert!(punycode_decode("99999999999").is_none());
+    }
+
+    #[test]
+    fn it_validates_address_literals() {
+        assert!(is_address_literal("[192.0.2.1]"));
+        assert!(is_address_literal("[IPv6:2001:db8::1]"));
+        assert!(!is_address_literal("[192.0.2.256]"));
+        assert!(!is_address_literal("[2001:db8::1]"));
+        assert!(!is_address_literal("192.0.2.1"));
+        assert!(!is_address_literal("[IPv6:]"));
+        assert!(!is_address_literal("[abcd\u{e9}x]"));
+    }
+
+}
@@ -3,16 +3,20 @@ mod transformer_tests {
 
     extern crate openssl;
 
-    use srs::transformers::{Receiver,ReceiverError,Forwarder,ForwarderError,Timestamper,SRSTimestamper};
+    use srs::clock::FixedClock;
+    use srs::error::SRSError;
+    use srs::transformers::{Receiver,Forwarder,Timestamper,SRSTimestamper,HourlyTimestamper,NoTimestamper,LibSRS2Timestamper,
+                            HashFormat,HashEncoding,
+                            ForwardableAddress,Forwarded,OverflowStrategy,SRSScheme};
     use srs::parser::SRSAddress;
     use openssl::hash::MessageDigest;
 
     struct MockTimestamper {
-        pub verify: Box<Fn(&str) -> Result<(), i32>>,
+        pub verify: Box<Fn(&str) -> Result<(), SRSError>>,
         pub now: Box<Fn() -> String>,
     }
     impl Timestamper for MockTimestamper {
-        fn verify_timestamp(&self, ts: &str) -> Result<(), i32> { (self.verify)(ts) }
+        fn verify_timestamp(&self, ts: &str) -> Result<(), SRSError> { (self.verify)(ts) }
         fn now_as_timestamp(&self) -> String { (self.now)() }
     }
 
@@ -22,7 +26,7 @@ mod transformer_tests {
             hostname.to_owned().into_bytes(),
             MessageDigest::sha512(),
             MockTimestamper{
-                verify: Box::new(|ts| Ok(())),
+                verify: Box::new(|_| Ok(())),
                 now: Box::new(|| "AA".to_string()),
             },
             ).expect("test should assert receiver params are ok");
@@ -39,7 +43,7 @@ mod transformer_tests {
     }
 
     fn expect_receive_err<F,T>(receiver: &Receiver<T>, input: &str, match_err: F)
-        where F: FnOnce(ReceiverError) -> bool,
+        where F: FnOnce(SRSError) -> bool,
               T: Timestamper {
         let input_srs = SRSAddress::from_string(input)
             .expect("test should supply valid srs addresss");
@@ -62,7 +66,7 @@ mod transformer_tests {
     fn it_validates_srs0_hmac_and_reports_expected_hmac_on_failure() {
         let b = make_receiver("asecret", "b");
         let check_error = |r| match r {
-            ReceiverError::HashVerificationFailed(correct) => {
+            SRSError::HashMismatch{ expected: correct } => {
                 println!("received hash verification error: should have been: {:?}", correct);
                 correct == "uNjN".to_string()
             },
@@ -75,7 +79,7 @@ mod transformer_tests {
     fn it_validates_srs1_hmac_and_reports_expected_hmac_on_failure() {
         let c = make_receiver("csecret", "c");
         let check_error = |r| match r {
-            ReceiverError::HashVerificationFailed(correct) => {
+            SRSError::HashMismatch{ expected: correct } => {
                 println!("received hash verification error: should have been: {:?}", correct);
                 correct == "nAM6".to_string()
             },
@@ -100,13 +104,13 @@ mod transformer_tests {
         b.timestamper = MockTimestamper{
             verify: Box::new(|ts| match ts == "ac" {
                 true => Ok(()),
-                false => Err(23),
+                false => Err(SRSError::TimestampExpired{ age: 23 }),
             }),
             now: Box::new(|| panic!("shouldn't be called")),
         };
         expect_receive(&b, "SRS0=pt9d=ac=a=user@b", "user@a");
         expect_receive_err(&b, "SRS0=tH4m=ae=a=user@b", |e| match e {
-            ReceiverError::TimestampError(23) => true,
+            SRSError::TimestampExpired{ age: 23 } => true,
             x => {
                 println!("{:?}", x);
                 false
@@ -121,7 +125,7 @@ mod transformer_tests {
             MessageDigest::sha512(),
             "=",
             MockTimestamper{
-                verify: Box::new(|ts| Err(23)),
+                verify: Box::new(|_| Err(SRSError::TimestampExpired{ age: 23 })),
                 now: Box::new(|| "aa".to_string()),
             },
             ).expect("test should assert receiver params are ok");
@@ -132,7 +136,7 @@ mod transformer_tests {
 
         let f = make_forwarder("asecret", "a");
 
-        use srs::transformers::{ForwardableAddress,Forwarder};
+        use srs::transformers::ForwardableAddress;
 
         let plain = ForwardableAddress::Plain{
             local: "user".to_string(),
@@ -144,7 +148,7 @@ mod transformer_tests {
         assert!(res.is_ok());
         let res = res.unwrap();
         assert!(res.is_0());
-        let mut res = res.srs0();
+        let res = res.srs0();
         assert!(res.hostname == "origin");
         assert!(res.local == "user");
         assert!(res.domain == "a");
@@ -156,15 +160,25 @@ mod transformer_tests {
 
     }
 
+    #[test]
+    fn it_does_not_forward_the_null_sender() {
+        let f = make_forwarder("asecret", "a");
+        match f.forward(ForwardableAddress::Null) {
+            Err(SRSError::NullSender) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
     #[test]
     fn it_adds_srs1_prefix_to_srs0() {
         let f = make_forwarder("bsecret", "b");
 
-        use srs::transformers::{ForwardableAddress,Forwarder};
-        use srs::parser::SRSAddress::{SRS0,SRS1};
+        use srs::transformers::ForwardableAddress;
+        use srs::parser::SRSAddress::SRS0;
         use srs::parser::SRS0Address;
 
         let srs0 = ForwardableAddress::SRS(SRS0(SRS0Address{
+            prefix: "SRS0".to_string(),
             separator: "+".to_string(),
             hash: "HHHH".to_string(),
             tt: "TT".to_string(),
@@ -178,7 +192,7 @@ mod transformer_tests {
         assert!(res.is_ok());
         let res = res.unwrap();
         assert!(res.is_1());
-        let mut res = res.srs1();
+        let res = res.srs1();
 
         assert!(res.hostname == "a");
         assert!(res.domain == "b");
@@ -194,11 +208,12 @@ mod transformer_tests {
     fn it_updates_domain_on_srs1_address() {
         let f = make_forwarder("csecret", "c");
 
-        use srs::transformers::{ForwardableAddress,Forwarder};
-        use srs::parser::SRSAddress::{SRS0,SRS1};
+        use srs::transformers::ForwardableAddress;
+        use srs::parser::SRSAddress::SRS1;
         use srs::parser::SRS1Address;
 
         let srs1 = ForwardableAddress::SRS(SRS1(SRS1Address{
+            prefix: "SRS1".to_string(),
             separator: "=".to_string(),
             hash: "HBHB".to_string(),
             hostname: "a".to_string(),
@@ -223,7 +238,6 @@ mod transformer_tests {
     #[test]
     fn it_handles_full_chain_simulation() {
 
-        use srs::transformers::{ForwardableAddress,Forwarder};
         use srs::transformers::ForwardableAddress::{Plain,SRS};
         use srs::parser::SRSAddress;
 
@@ -238,7 +252,6 @@ mod transformer_tests {
         let f_c = make_forwarder("csecret", "c");
         let r_a = make_receiver("asecret", "a");
         let r_b = make_receiver("bsecret", "b");
-        let r_c = make_receiver("csecret", "c");
 
         let res = f_a.forward(plain)
             .and_then(|x| f_b.forward(SRS(x)))
@@ -275,34 +288,676 @@ mod transformer_tests {
 
     }
 
+    fn forward_chain_with_scheme(scheme: SRSScheme) -> (SRSAddress, Vec<Receiver<MockTimestamper>>) {
+        use srs::transformers::ForwardableAddress::{Plain,SRS};
+
+        let mut forwarders = vec![make_forwarder("asecret", "a"), make_forwarder("bsecret", "b"), make_forwarder("csecret", "c")];
+        let mut receivers = vec![make_receiver("asecret", "a"), make_receiver("bsecret", "b"), make_receiver("csecret", "c")];
+        for f in &mut forwarders {
+            f.scheme = scheme;
+        }
+        for r in &mut receivers {
+            r.scheme = scheme;
+        }
+
+        let res = forwarders[0].forward(Plain{
+                local: "user".to_string(),
+                domain: "origin".to_string(),
+            })
+            .and_then(|x| forwarders[1].forward(SRS(x)))
+            .and_then(|x| forwarders[2].forward(SRS(x)))
+            .expect("forwarding should work");
+        println!("{:?}", res);
+        return (res, receivers);
+    }
+
+    fn receive_str<T>(r: &Receiver<T>, address: &str) -> String where T: Timestamper {
+        let address = SRSAddress::from_string(address).expect("should be a valid srs address");
+        return r.receive(&address).expect("should receive");
+    }
+
     #[test]
-    fn srstimestamper_respects_max_valid_delta() {
-        let mut t = SRSTimestamper {
-            max_valid_delta: 3
+    fn it_handles_full_chain_simulation_with_guarded_scheme() {
+        let (res, r) = forward_chain_with_scheme(SRSScheme::Guarded);
+        // C only changed the domain of B's SRS1, which points to A
+        assert!(res.is_1());
+        let res = r[1].receive(&res).expect("b should receive");
+        assert!(res.starts_with("SRS0=") && res.ends_with("=origin=user@a"));
+        assert!(receive_str(&r[0], &res) == "user@origin");
+    }
+
+    #[test]
+    fn it_handles_full_chain_simulation_with_shortcut_scheme() {
+        let (res, r) = forward_chain_with_scheme(SRSScheme::Shortcut);
+        // C's SRS0 points straight at the original sender
+        let srs0 = res.srs0();
+        assert!(srs0.hostname == "origin" && srs0.local == "user" && srs0.domain == "c");
+        assert!(r[2].receive(&SRSAddress::SRS0(srs0)).expect("c should receive") == "user@origin");
+        // Shortcut never issues SRS1
+        let err = r[1].receive(&SRSAddress::from_string("SRS1=nAM6=b==M59m=TT=a=user@c").unwrap());
+        match err {
+            Err(SRSError::SchemeMismatch) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn it_handles_full_chain_simulation_with_reversible_scheme() {
+        let (res, r) = forward_chain_with_scheme(SRSScheme::Reversible);
+        // Every hop nests the previous address
+        assert!(res.is_0());
+        let res = r[2].receive(&res).expect("c should receive");
+        assert!(res.starts_with("SRS0=") && res.ends_with("@b"));
+        let res = receive_str(&r[1], &res);
+        assert!(res.starts_with("SRS0=") && res.ends_with("=origin=user@a"));
+        assert!(receive_str(&r[0], &res) == "user@origin");
+    }
+
+    #[test]
+    fn it_verifies_own_srs1_in_strict_mode() {
+        let mut c = make_forwarder("csecret", "c");
+        c.strict = true;
+        let genuine = SRSAddress::from_string("SRS1=nAM6=b==M59m=TT=a=user@c").unwrap();
+        let res = c.forward(ForwardableAddress::SRS(genuine)).expect("genuine SRS1 should be forwarded");
+        assert!(res.srs1().hash == "nAM6");
+
+        for forged in &["SRS1=HHHH=b==M59m=TT=a=user@c", "SRS1=HHHH=b==M59m=TT=a=user@C"] {
+            match c.forward(ForwardableAddress::SRS(SRSAddress::from_string(forged).unwrap())) {
+                Err(SRSError::ForgedSRS1) => (),
+                x => panic!("unexpected result {:?}", x),
+            }
+        }
+
+        c.own_domains = vec!["c2".to_string()];
+        match c.forward(ForwardableAddress::SRS(SRSAddress::from_string("SRS1=HHHH=b==M59m=TT=a=user@c2").unwrap())) {
+            Err(SRSError::ForgedSRS1) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn it_resigns_foreign_srs1_in_strict_mode() {
+        let mut d = make_forwarder("dsecret", "d");
+        let input = "SRS1=HHHH=b==M59m=TT=a=user@c";
+
+        let res = d.forward(ForwardableAddress::SRS(SRSAddress::from_string(input).unwrap())).unwrap();
+        assert!(res.srs1().hash == "HHHH");
+
+        d.strict = true;
+        let res = d.forward(ForwardableAddress::SRS(SRSAddress::from_string(input).unwrap())).unwrap();
+        assert!(res.is_1());
+        // bounces now come back to d, which can verify its own signature
+        let r = make_receiver("dsecret", "d");
+        assert!(r.receive(&res).expect("d should verify") == "SRS0=M59m=TT=a=user@b");
+    }
+
+    fn make_receiver_with_secrets(keys: Vec<&str>, hostname: &str) -> Receiver<MockTimestamper> {
+        return Receiver::with_secrets(
+            keys.iter().map(|k| k.to_string().into_bytes()).collect(),
+            hostname.to_owned().into_bytes(),
+            MessageDigest::sha512(),
+            MockTimestamper{
+                verify: Box::new(|_| Ok(())),
+                now: Box::new(|| "AA".to_string()),
+            },
+            ).expect("test should assert receiver params are ok");
+    }
+
+    #[test]
+    fn it_accepts_hashes_made_with_any_secret_and_reports_the_key() {
+        let b = make_receiver_with_secrets(vec!["newsecret", "bsecret"], "b");
+        let addr = SRSAddress::from_string("SRS0=M59m=TT=a=user@b").expect("valid srs address");
+        let (rewritten, key_index) = b.receive_with_key_index(&addr).expect("old secret should still verify");
+        assert!(rewritten == "user@a");
+        assert!(key_index == 1);
+    }
+
+    #[test]
+    fn it_reports_the_newest_secrets_hash_on_failure() {
+        let b = make_receiver_with_secrets(vec!["asecret", "bsecret"], "b");
+        expect_receive_err(&b, "SRS0=HHHH=TT=a=user@b", |r| match r {
+            SRSError::HashMismatch{ expected: correct } => correct == "uNjN".to_string(),
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn it_refuses_empty_secret_lists() {
+        let r = Receiver::with_secrets(vec![], "b".to_owned().into_bytes(), MessageDigest::sha512(),
+                                       SRSTimestamper::new(0));
+        assert!(r.is_err());
+        let f = Forwarder::with_secrets(vec![], "b".to_owned().into_bytes(), MessageDigest::sha512(), "=",
+                                        SRSTimestamper::new(0));
+        assert!(f.is_err());
+    }
+
+    #[test]
+    fn it_refuses_invalid_hostnames() {
+        for hostname in &["", "a b", "a@b", "a=b", "a..b", "-a.b", "ab--c.example", "b\u{fc}cher.example"] {
+            let r = Receiver::new(b"bsecret".to_vec(), hostname.as_bytes().to_vec(), MessageDigest::sha512(),
+                                  SRSTimestamper::new(0));
+            assert!(r.is_err(), "{:?} should be refused", hostname);
+        }
+        let r = Receiver::new(b"bsecret".to_vec(), b"xn--bcher-kva.example".to_vec(), MessageDigest::sha512(),
+                              SRSTimestamper::new(0));
+        assert!(r.is_ok());
+        // the hostname must not contain the separator
+        for &(separator, ok) in &[("=", true), ("+", true), ("-", false)] {
+            let f = Forwarder::new(b"bsecret".to_vec(), b"mail-1.example".to_vec(), MessageDigest::sha512(), separator,
+                                   SRSTimestamper::new(0));
+            match f {
+                Err(SRSError::InvalidHostname) => assert!(!ok),
+                Ok(_)                          => assert!(ok),
+                Err(e)                         => panic!("unexpected error {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn it_refuses_to_forward_invalid_senders() {
+        let f = make_forwarder("bsecret", "b");
+        for &(local, domain) in &[("\"john", "a"), ("a..b", "a"), (".a", "a"), ("a b", "a"), ("j\u{f6}rg", "a"),
+                                  ("user", "exa mple"), ("user", "a_b"), ("user", "[192.0.2.256]"), ("user", "")] {
+            match f.forward(ForwardableAddress::Plain{ local: local.to_string(), domain: domain.to_string() }) {
+                Err(SRSError::InvalidSender) => (),
+                x => panic!("unexpected result for {:?}@{:?}: {:?}", local, domain, x),
+            }
+        }
+        for &(local, domain) in &[("first.last+tag", "a.example"), ("user", "[192.0.2.1]"), ("user", "[IPv6:2001:db8::1]")] {
+            assert!(f.forward(ForwardableAddress::Plain{ local: local.to_string(), domain: domain.to_string() }).is_ok());
+        }
+        let srs = SRSAddress::from_string("SRS0=HHH=TT=a=user@c=d").expect("should parse");
+        match f.forward(ForwardableAddress::SRS(srs)) {
+            Err(SRSError::InvalidSender) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn it_leaves_quoted_local_parts_alone() {
+        let f = make_forwarder("bsecret", "b");
+        for local in &["\"john doe\"", "\"a..b\"", "\"j\\\"d\""] {
+            match f.forward(ForwardableAddress::Plain{ local: local.to_string(), domain: "a".to_string() }) {
+                Err(SRSError::QuotedLocalPart) => (),
+                x => panic!("unexpected result for {:?}: {:?}", local, x),
+            }
+        }
+    }
+
+    #[test]
+    fn it_signs_with_the_newest_secret() {
+        let f = Forwarder::with_secrets(vec!["newsecret".to_owned().into_bytes(), "oldsecret".to_owned().into_bytes()],
+                                        "a".to_owned().into_bytes(), MessageDigest::sha512(), "=",
+                                        MockTimestamper{
+                                            verify: Box::new(|_| Err(SRSError::TimestampExpired{ age: 23 })),
+                                            now: Box::new(|| "aa".to_string()),
+                                        }).expect("test should assert forwarder params are ok");
+        let res = f.forward(ForwardableAddress::Plain{
+            local: "user".to_string(),
+            domain: "origin".to_string(),
+        }).expect("forwarding should work");
+
+        let r = make_receiver_with_secrets(vec!["newsecret", "oldsecret"], "a");
+        let (_, key_index) = r.receive_with_key_index(&res).expect("hash should verify");
+        assert!(key_index == 0);
+    }
+
+    #[test]
+    fn it_issues_hashes_of_the_configured_length() {
+        let mut f = make_forwarder("bsecret", "b");
+        let mut r = make_receiver("bsecret", "b");
+        for &length in &[4, 5, 8, 10, 24] {
+            f.hash_length = length;
+            r.hash_length = length;
+            r.hash_min_length = length;
+            let res = f.forward(ForwardableAddress::Plain{
+                local: "user".to_string(),
+                domain: "a".to_string(),
+            }).expect("forwarding should work");
+            let srs0 = res.srs0();
+            assert!(srs0.hash.len() == length);
+            assert!(r.receive(&SRSAddress::SRS0(srs0)).is_ok());
+        }
+    }
+
+    #[test]
+    fn it_accepts_hashes_down_to_the_minimum_length() {
+        let mut b = make_receiver("bsecret", "b");
+        b.hash_length = 8;
+        b.hash_min_length = 4;
+        let full = match b.receive(&SRSAddress::from_string("SRS0=HHHH=TT=a=user@b").unwrap()) {
+            Err(SRSError::HashMismatch{ expected: full }) => full,
+            x => panic!("unexpected result {:?}", x),
         };
+        assert!(full.len() == 8 && full.starts_with("M59m"));
+        expect_receive(&b, &format!("SRS0={}=TT=a=user@b", full), "user@a");
+        expect_receive(&b, "SRS0=M59m=TT=a=user@b", "user@a");
+        expect_receive_err(&b, "SRS0=M59=TT=a=user@b", |e| match e {
+            SRSError::HashTooShort{ len: 3, min: 4 } => true,
+            _ => false,
+        });
+        expect_receive_err(&b, &format!("SRS0={}X=TT=a=user@b", full), |e| match e {
+            SRSError::HashMismatch{..} => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn it_never_accepts_an_empty_hash() {
+        let b = make_receiver("bsecret", "b");
+        expect_receive_err(&b, "SRS0==TT=a=user@b", |e| match e {
+            SRSError::HashTooShort{ len: 0, min: 4 } => true,
+            _ => false,
+        });
+        expect_receive_err(&b, "SRS1==a==HHH=TT=origin=user@b", |e| match e {
+            SRSError::HashTooShort{ len: 0, min: 4 } => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn it_refuses_hash_lengths_below_the_minimum() {
+        let mut b = make_receiver("bsecret", "b");
+        for &min in &[0, 1, 2, 3] {
+            b.hash_min_length = min;
+            expect_receive_err(&b, "SRS0=M59m=TT=a=user@b", |e| match e {
+                SRSError::InvalidHashLength(l) => l == min,
+                _ => false,
+            });
+        }
+        let mut f = make_forwarder("bsecret", "b");
+        f.hash_length = 2;
+        match f.forward(ForwardableAddress::Plain{ local: "user".to_string(), domain: "a".to_string() }) {
+            Err(SRSError::InvalidHashLength(2)) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+        f.hash_length = 8;
+        f.hash_min_length = 3;
+        match f.forward(ForwardableAddress::Plain{ local: "user".to_string(), domain: "a".to_string() }) {
+            Err(SRSError::InvalidHashLength(3)) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    fn forward_long_sender<T>(f: &Forwarder<T>, local_len: usize) -> Result<Forwarded, SRSError>
+        where T: Timestamper {
+        return f.forward_checked(ForwardableAddress::Plain{
+            local: "x".repeat(local_len),
+            domain: "origin".to_string(),
+        });
+    }
+
+    #[test]
+    fn it_limits_the_local_part_length() {
+        let f = make_forwarder("bsecret", "b");
+        // SRS0=HHHH=aa=origin= is 20 octets
+        match forward_long_sender(&f, 44) {
+            Ok(Forwarded::Fits(a)) => assert!(a.local_part_len() == 64),
+            x => panic!("unexpected result {:?}", x),
+        }
+        match forward_long_sender(&f, 45) {
+            Err(SRSError::LocalPartTooLong(65)) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+        match f.forward(ForwardableAddress::Plain{ local: "x".repeat(45), domain: "origin".to_string() }) {
+            Err(SRSError::LocalPartTooLong(65)) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn it_compresses_by_shortening_the_hash() {
+        let mut f = make_forwarder("bsecret", "b");
+        let mut r = make_receiver("bsecret", "b");
+        f.overflow_strategy = OverflowStrategy::Compress;
+        f.hash_length = 8;
+        f.hash_min_length = 4;
+        r.hash_length = 8;
+        r.hash_min_length = 4;
+        // 24 + 42 = 66 octets, 2 too many
+        let a = match forward_long_sender(&f, 42) {
+            Ok(Forwarded::Compressed(a)) => a,
+            x => panic!("unexpected result {:?}", x),
+        };
+        assert!(a.local_part_len() == 64);
+        let srs0 = a.srs0();
+        assert!(srs0.hash.len() == 6);
+        assert!(r.receive(&SRSAddress::SRS0(srs0)).is_ok());
+        // 24 + 45 = 69 octets, the hash would be shorter than 4
+        match forward_long_sender(&f, 45) {
+            Err(SRSError::LocalPartTooLong(69)) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn it_leaves_overlong_addresses_to_the_database() {
+        let mut f = make_forwarder("bsecret", "b");
+        f.overflow_strategy = OverflowStrategy::Database;
+        match forward_long_sender(&f, 45) {
+            Ok(Forwarded::Database(65)) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    fn forward_and_lowercase<T>(f: &Forwarder<T>) -> String where T: Timestamper {
+        let srs = f.forward(ForwardableAddress::Plain{
+            local: "John.Doe".to_string(),
+            domain: "Example.ORG".to_string(),
+        }).expect("forwarding should work").to_string();
+        return srs.to_lowercase();
+    }
+
+    #[test]
+    fn it_survives_lowercasing_with_case_folding_and_base32() {
+        let mut f = make_forwarder("bsecret", "b");
+        let mut r = make_receiver("bsecret", "b");
+        f.fold_case = true;
+        f.hash_encoding = HashEncoding::Base32;
+        r.fold_case = true;
+        r.hash_encoding = HashEncoding::Base32;
+        for &length in &[4, 8, 24] {
+            f.hash_length = length;
+            r.hash_length = length;
+            r.hash_min_length = length;
+            let lowercased = forward_and_lowercase(&f);
+            expect_receive(&r, &lowercased, "john.doe@example.org");
+        }
+    }
+
+    #[test]
+    fn it_does_not_survive_lowercasing_without_case_folding() {
+        let f = make_forwarder("bsecret", "b");
+        let r = make_receiver("bsecret", "b");
+        let lowercased = forward_and_lowercase(&f);
+        expect_receive_err(&r, &lowercased, |e| match e {
+            SRSError::HashMismatch{..} => true,
+            _ => false,
+        });
+    }
+
+    // Vectors as produced by libsrs2 / postsrsd with secret "tops3cr3t"
+    const LIBSRS2_SRS0: &'static str = "SRS0=XjO9=2V=otherdomain.com=test@example.com";
+    const LIBSRS2_SRS1: &'static str = "SRS1=yMb7=example.com==XjO9=2V=otherdomain.com=test@example.com";
+
+    #[test]
+    fn it_reverses_libsrs2_addresses() {
+        let mut r = make_receiver("tops3cr3t", "example.com");
+        r.hash_format = HashFormat::LibSRS2;
+        expect_receive(&r, LIBSRS2_SRS0, "test@otherdomain.com");
+        // libsrs2 lowercases before hashing
+        expect_receive(&r, "SRS0=XjO9=2v=OtherDomain.COM=Test@example.com", "Test@OtherDomain.COM");
+        expect_receive(&r, LIBSRS2_SRS1, "SRS0=XjO9=2V=otherdomain.com=test@example.com");
+
+        r.hash_format = HashFormat::Native;
+        expect_receive_err(&r, LIBSRS2_SRS0, |e| match e {
+            SRSError::HashMismatch{..} => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn it_forwards_like_libsrs2() {
+        let mut f = make_forwarder("tops3cr3t", "example.com");
+        f.hash_format = HashFormat::LibSRS2;
+        f.timestamper.now = Box::new(|| "2V".to_string());
+
+        let srs0 = f.forward(ForwardableAddress::Plain{
+            local: "test".to_string(),
+            domain: "otherdomain.com".to_string(),
+        }).expect("forwarding should work").srs0();
+        assert!(srs0.hash == "XjO9");
+        assert!(srs0.tt == "2V");
+
+        let srs1 = f.forward(ForwardableAddress::SRS(SRSAddress::from_string(LIBSRS2_SRS0).unwrap()))
+            .expect("forwarding should work").srs1();
+        assert!(srs1.hash == "yMb7");
+        assert!(srs1.opaque_local == "=XjO9=2V=otherdomain.com=test");
+    }
+
+    #[test]
+    fn libsrs2timestamper_encodes_high_bits_first() {
+        assert!(LibSRS2Timestamper::encode_10bit(568) == "RY");
+        assert!(LibSRS2Timestamper::decode_10bit("RY").ok() == Some(568));
+        assert!(LibSRS2Timestamper::decode_10bit("ry").ok() == Some(568));
+        assert!(LibSRS2Timestamper::decode_10bit("R1").is_err());
+        assert!(LibSRS2Timestamper::decode_10bit("RYA").is_err());
+        for i in 0..1024 {
+            assert!(LibSRS2Timestamper::decode_10bit(&LibSRS2Timestamper::encode_10bit(i)).ok() == Some(i));
+        }
+    }
+
+    #[test]
+    fn libsrs2timestamper_wraps_around() {
+        let ts = LibSRS2Timestamper::encode_10bit(1020);
+        let on_day = |day| LibSRS2Timestamper::with_clock(10, FixedClock::at_day(day)).verify_timestamp(&ts);
+        assert!(on_day(1020).is_ok());
+        assert!(on_day(1024 + 3).is_ok());
+        assert!(on_day(1024 + 6).is_ok());
+        match on_day(1024 + 7) {
+            Err(SRSError::TimestampExpired{ age: 11 }) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+        // from the future, i.e. almost a full cycle old
+        match on_day(1024 + 1019) {
+            Err(SRSError::TimestampExpired{ age: 1023 }) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn libsrs2timestamper_expires_on_its_clock() {
+        // 2017-03-01, day 17226, which is 842 modulo 1024
+        let issued = 17226;
+        let ts = LibSRS2Timestamper::with_clock(21, FixedClock::at_day(issued)).now_as_timestamp();
+        assert!(ts == "2K");
+        for &(day, ok) in &[(issued, true), (issued + 21, true), (issued + 22, false), (issued + 1024, true)] {
+            let t = LibSRS2Timestamper::with_clock(21, FixedClock::at_day(day));
+            assert!(t.verify_timestamp(&ts).is_ok() == ok, "unexpected result on day {}", day);
+        }
+    }
+
+    #[test]
+    fn srstimestamper_respects_max_valid_delta() {
+        let t = SRSTimestamper::new(3);
 
         let now = SRSTimestamper::now_in_days_10bit();
-        let good_date = (now + 3) % 1024;
-        let bad_date  = (now + 6) % 1024;
+        let good_date = (now + 1024 - 3) % 1024;
+        let bad_date  = (now + 1024 - 6) % 1024;
 
         let good_ts = SRSTimestamper::base32_email_safe_encode_10bit(good_date);
         let bad_ts = SRSTimestamper::base32_email_safe_encode_10bit(bad_date);
 
         let r = t.verify_timestamp(&good_ts);
         println!("{:?}", r);
-        assert!(r == Ok(()));
+        assert!(r.is_ok());
 
         let r = t.verify_timestamp(&bad_ts);
         println!("{:?}", r);
-        assert!(r == Err(6));
+        match r {
+            Err(SRSError::TimestampExpired{ age: 6 }) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
     }
 
     #[test]
-    fn srstimestamper_base32_works_for_example() {
-        let t = SRSTimestamper {
-            max_valid_delta: 0
-        };
+    fn srstimestamper_wraps_around() {
+        fn on_day(ts: u16, day: u64) -> Result<(), SRSError> {
+            let mut t = SRSTimestamper::with_clock(10, FixedClock::at_day(day));
+            t.max_future_delta = 2;
+            return t.verify_timestamp(&SRSTimestamper::base32_email_safe_encode_10bit(ts));
+        }
+        for &day in &[1018, 1019, 1020, 1023, 1024, 1024 + 3, 1024 + 6] {
+            assert!(on_day(1020, day).is_ok(), "should be valid on day {}", day);
+        }
+        match on_day(1020, 1024 + 7) {
+            Err(SRSError::TimestampExpired{ age: 11 }) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+        match on_day(1020, 1017) {
+            Err(SRSError::TimestampExpired{ age: -3 }) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+        // yesterday, across the wrap
+        assert!(on_day(1023, 1024).is_ok());
+        // the far past is not mistaken for the future, and the other way around
+        match on_day(1023, 1024 + 500) {
+            Err(SRSError::TimestampExpired{ age: 501 }) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+        match on_day(1023, 900) {
+            Err(SRSError::TimestampExpired{ age: -123 }) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
 
+    #[test]
+    fn it_expires_addresses_on_the_receivers_clock() {
+        // issued 2017-03-01, day 17226, which is 842 modulo 1024
+        let issued = 17226;
+        let f = Forwarder::new(b"bsecret".to_vec(), b"b".to_vec(), MessageDigest::sha512(), "=",
+                               SRSTimestamper::with_clock(21, FixedClock::at_day(issued)))
+            .expect("test should assert forwarder params are ok");
+        let srs = f.forward(ForwardableAddress::Plain{
+            local: "user".to_string(),
+            domain: "a".to_string(),
+        }).expect("forwarding should work");
+        match srs {
+            SRSAddress::SRS0(ref a) => assert!(a.tt == "k2"),
+            ref x                   => panic!("unexpected address {:?}", x),
+        }
+
+        // the bounce arrives up to a day early, 21 days late and across the wrap on day 18432
+        for &(day, ok) in &[(issued - 2, false), (issued - 1, true), (issued + 21, true), (issued + 22, false),
+                            (18431, false), (issued + 1024, true)] {
+            let r = Receiver::new(b"bsecret".to_vec(), b"b".to_vec(), MessageDigest::sha512(),
+                                  SRSTimestamper::with_clock(21, FixedClock::at_day(day)))
+                .expect("test should assert receiver params are ok");
+            match r.receive(&srs) {
+                Ok(ref a) if ok && a == "user@a" => (),
+                Err(SRSError::TimestampExpired{..}) if !ok => (),
+                x => panic!("unexpected result on day {}: {:?}", day, x),
+            }
+        }
+    }
+
+    #[test]
+    fn srstimestamper_limits_future_timestamps_separately() {
+        fn on_day(ts: u16, day: u64, max_future_delta: u16) -> Result<(), SRSError> {
+            let mut t = SRSTimestamper::with_clock(21, FixedClock::at_day(day));
+            t.max_future_delta = max_future_delta;
+            return t.verify_timestamp(&SRSTimestamper::base32_email_safe_encode_10bit(ts));
+        }
+        assert!(on_day(5, 4, 1).is_ok());
+        assert!(on_day(5, 3, 1).is_err());
+        assert!(on_day(5, 4, 0).is_err());
+        assert!(on_day(5, 5, 0).is_ok());
+        // issued on day 0 of the next cycle, checked on day 1023
+        assert!(on_day(0, 1023, 1).is_ok());
+        assert!(on_day(0, 2047, 1).is_ok());
+        match on_day(0, 1023, 0) {
+            Err(SRSError::TimestampExpired{ age: -1 }) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+        match on_day(1, 1023, 1) {
+            Err(SRSError::TimestampExpired{ age: -2 }) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn hourlytimestamper_encodes_all_15bit_numbers() {
+        assert!(HourlyTimestamper::encode_15bit(0) == "aaa");
+        assert!(HourlyTimestamper::encode_15bit(23) == "xaa");
+        assert!(HourlyTimestamper::encode_15bit(32767) == "777");
+        for i in 0..(1 << 15) {
+            assert!(HourlyTimestamper::decode_15bit(&HourlyTimestamper::encode_15bit(i)).ok() == Some(i));
+        }
+        assert!(HourlyTimestamper::decode_15bit("XAA").ok() == Some(23));
+        for ts in &["", "aa", "aaaa", "aa1", "a\u{e9}"] {
+            match HourlyTimestamper::decode_15bit(ts) {
+                Err(SRSError::TimestampMalformed) => (),
+                x => panic!("unexpected result for {:?}: {:?}", ts, x),
+            }
+        }
+    }
+
+    #[test]
+    fn hourlytimestamper_expires_after_hours() {
+        // 2017-03-01 12:00
+        let issued = 1488369600;
+        let f = HourlyTimestamper::with_clock(6, FixedClock{ now: issued });
+        let ts = f.now_as_timestamp();
+        assert!(ts.len() == 3);
+        for &(offset, ok) in &[(-7200, false), (-3600, true), (0, true), (6 * 3600 + 3599, true), (7 * 3600, false)] {
+            let r = HourlyTimestamper::with_clock(6, FixedClock{ now: (issued as i64 + offset) as u64 });
+            assert!(r.verify_timestamp(&ts).is_ok() == ok, "unexpected result {} seconds after issuing", offset);
+        }
+        // wraps after 32768 hours
+        let t = HourlyTimestamper::new(6);
+        let ts = HourlyTimestamper::encode_15bit(32766);
+        assert!(t.verify_timestamp_at(&ts, 4).is_ok());
+        match t.verify_timestamp_at(&ts, 5) {
+            Err(SRSError::TimestampExpired{ age: 7 }) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn notimestamper_never_expires() {
+        let f = Forwarder::new(b"bsecret".to_vec(), b"b".to_vec(), MessageDigest::sha512(), "=", NoTimestamper)
+            .expect("test should assert forwarder params are ok");
+        let r = Receiver::new(b"bsecret".to_vec(), b"b".to_vec(), MessageDigest::sha512(), NoTimestamper)
+            .expect("test should assert receiver params are ok");
+        let srs = f.forward(ForwardableAddress::Plain{
+            local: "user".to_string(),
+            domain: "a".to_string(),
+        }).expect("forwarding should work").to_string();
+        assert!(srs.starts_with("SRS0=") && srs.contains("==a=user@b"));
+        let srs = SRSAddress::from_string(&srs).expect("should parse");
+        assert!(r.receive(&srs).ok() == Some("user@a".to_string()));
+        match NoTimestamper.verify_timestamp("zz") {
+            Err(SRSError::TimestampMalformed) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn notimestamper_rejects_hostname_shifted_into_the_timestamp() {
+        let f = Forwarder::new(b"bsecret".to_vec(), b"b".to_vec(), MessageDigest::sha512(), "=", NoTimestamper)
+            .expect("test should assert forwarder params are ok");
+        let r = Receiver::new(b"bsecret".to_vec(), b"b".to_vec(), MessageDigest::sha512(), NoTimestamper)
+            .expect("test should assert receiver params are ok");
+        let srs0 = f.forward(ForwardableAddress::Plain{
+            local: "user".to_string(),
+            domain: "xvictim.com".to_string(),
+        }).expect("forwarding should work").srs0();
+        // same HMAC input, tt || hostname || local
+        let forged = format!("SRS0={}=x=victim.com=user@b", srs0.hash);
+        expect_receive_err(&r, &forged, |e| match e {
+            SRSError::TimestampMalformed => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn timestampers_tell_malformed_from_expired() {
+        let t = SRSTimestamper::new(3);
+        for ts in &["", "a", "abc", "a1", "\u{e9}"] {
+            match t.verify_timestamp(ts) {
+                Err(SRSError::TimestampMalformed) => (),
+                x => panic!("unexpected result for {:?}: {:?}", ts, x),
+            }
+        }
+        let t = LibSRS2Timestamper::new(3);
+        match t.verify_timestamp("A") {
+            Err(SRSError::TimestampMalformed) => (),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn srstimestamper_base32_works_for_example() {
         let enc = SRSTimestamper::base32_email_safe_encode_10bit(23);
         println!("enc: {}", enc);
         assert!(enc == "xa");
@@ -316,10 +971,6 @@ mod transformer_tests {
 
     #[test]
     fn srstimestamper_base32_works_for_all_10bit_numbers() {
-        let t = SRSTimestamper {
-            max_valid_delta: 0
-        };
-
         for i in  0..1024 {
             let enc = SRSTimestamper::base32_email_safe_encode_10bit(i);
             let dec = SRSTimestamper::base32_email_safe_decode_10bit(&enc);
@@ -7,12 +7,16 @@ use self::openssl::error::ErrorStack;
 
 use std::ascii::AsciiExt;
 
-use srs::parser::{SRSAddress,SRS1Address,SRS0Address};
+use srs::clock::{Clock,SystemClock};
+use srs::error::SRSError;
+use srs::parser::{SRSAddress,SRS1Address,SRS0Address,SRSAddressRef,Charset};
 use srs::parser::SRSAddress::{SRS0,SRS1};
-use srs::util::{base64_email_safe_encode,base64_email_safe_decode};
+use srs::syntax;
+use srs::util::{base64_email_safe_encode,base64_email_safe_decode,base64_standard_encode,base32_email_safe_encode};
 
-use std::time;
-use std::i32;
+use std::cmp;
+use std::fmt;
+use std::str;
 
 /* A SHORT EXAMPLE:
  *
@@ -31,41 +35,66 @@ use std::i32;
  */
 
 pub trait Timestamper {
-    fn verify_timestamp(&self, ts: &str) -> Result<(), i32>;
+    /// Fails with `TimestampMalformed` or `TimestampExpired`
+    fn verify_timestamp(&self, ts: &str) -> Result<(), SRSError>;
     fn now_as_timestamp(&self) -> String;
 }
 
-pub struct SRSTimestamper {
+/// Timestamps are days modulo 1024, so ages are computed modulo 1024 as well.
+pub struct SRSTimestamper<C = SystemClock> where C: Clock {
+    /// Days a timestamp stays valid
     pub max_valid_delta: u16,
+    /// Days a timestamp may lie in the future, for forwarders whose clock is ahead
+    pub max_future_delta: u16,
+    pub clock: C,
+}
+
+/// Allowed clock skew between forwarder and receiver by default
+pub const DEFAULT_MAX_FUTURE_DELTA: u16 = 1;
+
+/// Counters wrap after `slots`, so `then` is either up to `max_past` before `now`
+/// or up to `max_future` after it, whichever is closer is reported.
+fn check_age(then: u16, now: u16, slots: i32, max_past: u16, max_future: u16) -> Result<(), SRSError> {
+    let age = (now as i32 + slots - then as i32) % slots;
+    if age <= max_past as i32 {
+        return Ok(());
+    }
+    let ahead = slots - age;
+    if ahead <= max_future as i32 {
+        return Ok(());
+    }
+    return match age <= ahead {
+        true  => Err(SRSError::TimestampExpired{ age: age }),
+        false => Err(SRSError::TimestampExpired{ age: -ahead }),
+    };
 }
 
 impl SRSTimestamper {
-   pub fn now_in_days_10bit() -> u16 {
-        let secs_since_epoch = time::SystemTime::now()
-            .duration_since(time::UNIX_EPOCH)
-            .expect("UNIX_EPOCH is always earlier than current time")
-            .as_secs();
-        // Break this down to days that fit into 10 bit
-        // => wraparound after > 3 years is fine
-        let days = (secs_since_epoch / (60 * 60 * 24)) % 1024;
-        assert!(days < 1024);
-        return days as u16;
-    }
-
-   pub fn base32_email_safe_decode_10bit(s: &str) -> Result<u16,()> {
+
+    pub fn new(max_valid_delta: u16) -> SRSTimestamper {
+        return Self::with_clock(max_valid_delta, SystemClock);
+    }
+
+    pub fn now_in_days_10bit() -> u16 {
+        return SystemClock.days_10bit();
+    }
+
+   pub fn base32_email_safe_decode_10bit(s: &str) -> Result<u16,SRSError> {
         let lowercase = s.to_ascii_lowercase();
         let bytes = lowercase.as_bytes();
-        assert!(bytes.len() >= 2);
+        if bytes.len() != 2 {
+            return Err(SRSError::TimestampMalformed);
+        }
         let mut res: u16 = 0;
 
-        fn lowercase_ascii_to_value(a: u8) -> Result<u8, ()> {
+        fn lowercase_ascii_to_value(a: u8) -> Result<u8, SRSError> {
             if a >= 97 && a <= 122 { // a to z
                 return Ok(a - 97);
             }
             if a >= 50 && a <= 55 { // 2 to 5
                 return Ok(26 + a - 50);
             }
-            return Err(());
+            return Err(SRSError::TimestampMalformed);
         }
 
         let val_low = try!(lowercase_ascii_to_value(bytes[0]));
@@ -99,153 +128,485 @@ impl SRSTimestamper {
 
 }
 
-impl Timestamper for SRSTimestamper  {
+impl<C> SRSTimestamper<C> where C: Clock {
 
-      fn verify_timestamp(&self, ts: &str) -> Result<(), i32> {
-        let now = Self::now_in_days_10bit();
-        let days_ts = match Self::base32_email_safe_decode_10bit(ts) {
-            Ok(d)  => d,
-            Err(_) => {
-                return Err(i32::MAX); // TODO hardcoded magic number
-            },
+    pub fn with_clock(max_valid_delta: u16, clock: C) -> SRSTimestamper<C> {
+        return SRSTimestamper{
+            max_valid_delta: max_valid_delta,
+            max_future_delta: DEFAULT_MAX_FUTURE_DELTA,
+            clock: clock,
         };
-        let abs_delta = match now > days_ts {
-            true => (now as i32) - (days_ts as i32),
-            false => (days_ts as i32) - (now as i32),
+    }
+
+    /// Like `verify_timestamp`, on day `now` (in days since the epoch, modulo 1024).
+    /// Timestamps from the future are reported with a negative age.
+    pub fn verify_timestamp_at(&self, ts: &str, now: u16) -> Result<(), SRSError> {
+        let then = try!(SRSTimestamper::base32_email_safe_decode_10bit(ts));
+        return check_age(then, now, 1024, self.max_valid_delta, self.max_future_delta);
+    }
+
+}
+
+impl<C> Timestamper for SRSTimestamper<C> where C: Clock {
+
+    fn verify_timestamp(&self, ts: &str) -> Result<(), SRSError> {
+        return self.verify_timestamp_at(ts, self.clock.days_10bit());
+    }
+
+    fn now_as_timestamp(&self) -> String {
+        let now = self.clock.days_10bit();
+        return SRSTimestamper::base32_email_safe_encode_10bit(now);
+    }
+
+}
+
+/// Hours since the epoch modulo 32768 in 3 base32 chars, low bits first like
+/// `SRSTimestamper`. For domains where a window of days is too long.
+pub struct HourlyTimestamper<C = SystemClock> where C: Clock {
+    /// Hours a timestamp stays valid
+    pub max_valid_delta: u16,
+    /// Hours a timestamp may lie in the future
+    pub max_future_delta: u16,
+    pub clock: C,
+}
+
+const BASE32_EMAIL_SAFE_CHARS: &'static [u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+impl HourlyTimestamper {
+
+    pub fn new(max_valid_delta: u16) -> HourlyTimestamper {
+        return Self::with_clock(max_valid_delta, SystemClock);
+    }
+
+    pub fn encode_15bit(hours: u16) -> String {
+        let bytes = (0..3)
+            .map(|i| BASE32_EMAIL_SAFE_CHARS[((hours >> (5 * i)) & 0x1f) as usize])
+            .collect();
+        return String::from_utf8(bytes).expect("routine should only produce ASCII bytes");
+    }
+
+    pub fn decode_15bit(s: &str) -> Result<u16, SRSError> {
+        if s.len() != 3 {
+            return Err(SRSError::TimestampMalformed);
+        }
+        let mut res: u16 = 0;
+        for (i, c) in s.to_ascii_lowercase().bytes().enumerate() {
+            let val = match BASE32_EMAIL_SAFE_CHARS.iter().position(|&b| b == c) {
+                Some(v) => v as u16,
+                None    => return Err(SRSError::TimestampMalformed),
+            };
+            res |= val << (5 * i);
+        }
+        return Ok(res);
+    }
+
+}
+
+impl<C> HourlyTimestamper<C> where C: Clock {
+
+    pub fn with_clock(max_valid_delta: u16, clock: C) -> HourlyTimestamper<C> {
+        return HourlyTimestamper{
+            max_valid_delta: max_valid_delta,
+            max_future_delta: DEFAULT_MAX_FUTURE_DELTA,
+            clock: clock,
+        };
+    }
+
+    fn now_in_hours_15bit(&self) -> u16 {
+        return ((self.clock.now() / (60 * 60)) % (1 << 15)) as u16;
+    }
+
+    /// Like `verify_timestamp`, on hour `now` (in hours since the epoch, modulo 32768)
+    pub fn verify_timestamp_at(&self, ts: &str, now: u16) -> Result<(), SRSError> {
+        let then = try!(HourlyTimestamper::decode_15bit(ts));
+        return check_age(then, now, 1 << 15, self.max_valid_delta, self.max_future_delta);
+    }
+
+}
+
+impl<C> Timestamper for HourlyTimestamper<C> where C: Clock {
+
+    fn verify_timestamp(&self, ts: &str) -> Result<(), SRSError> {
+        return self.verify_timestamp_at(ts, self.now_in_hours_15bit());
+    }
+
+    fn now_as_timestamp(&self) -> String {
+        return HourlyTimestamper::encode_15bit(self.now_in_hours_15bit());
+    }
+
+}
+
+/// Issues empty timestamps and accepts only those, for peers that predate them.
+/// Addresses never expire, so a valid one can be replayed forever.
+pub struct NoTimestamper;
+
+impl Timestamper for NoTimestamper {
+
+    fn verify_timestamp(&self, ts: &str) -> Result<(), SRSError> {
+        // The hash runs over timestamp and hostname without a delimiter, accepting
+        // other timestamps would let characters move from the hostname into it
+        match ts.is_empty() {
+            true  => Ok(()),
+            false => Err(SRSError::TimestampMalformed),
+        }
+    }
+
+    fn now_as_timestamp(&self) -> String {
+        return String::new();
+    }
+
+}
+
+/// Timestamps as issued by libsrs2 / postsrsd: high 5 bits first, uppercase.
+/// Timestamps from the future are taken to be from the previous 1024 day cycle.
+pub struct LibSRS2Timestamper<C = SystemClock> where C: Clock {
+    pub max_valid_delta: u16,
+    pub clock: C,
+}
+
+const LIBSRS2_TIME_BASECHARS: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+impl LibSRS2Timestamper {
+
+    pub fn new(max_valid_delta: u16) -> LibSRS2Timestamper {
+        return Self::with_clock(max_valid_delta, SystemClock);
+    }
+
+    pub fn encode_10bit(days: u16) -> String {
+        let bytes = vec![
+            LIBSRS2_TIME_BASECHARS[((days >> 5) & 0x1f) as usize],
+            LIBSRS2_TIME_BASECHARS[(days & 0x1f) as usize],
+        ];
+        return String::from_utf8(bytes).expect("routine should only produce ASCII bytes");
+    }
+
+    pub fn decode_10bit(s: &str) -> Result<u16, SRSError> {
+        if s.len() != 2 {
+            return Err(SRSError::TimestampMalformed);
+        }
+        let mut res: u16 = 0;
+        for c in s.to_ascii_uppercase().bytes() {
+            let val = match LIBSRS2_TIME_BASECHARS.iter().position(|&b| b == c) {
+                Some(v) => v as u16,
+                None    => return Err(SRSError::TimestampMalformed),
+            };
+            res = (res << 5) | val;
+        }
+        return Ok(res);
+    }
+
+}
+
+impl<C> LibSRS2Timestamper<C> where C: Clock {
+
+    pub fn with_clock(max_valid_delta: u16, clock: C) -> LibSRS2Timestamper<C> {
+        return LibSRS2Timestamper{
+            max_valid_delta: max_valid_delta,
+            clock: clock,
         };
+    }
 
-        if abs_delta > self.max_valid_delta as i32  {
-            return Err(abs_delta);
+    /// Like `verify_timestamp`, on day `now` (in days since the epoch, modulo 1024)
+    pub fn verify_timestamp_at(&self, ts: &str, now: u16) -> Result<(), SRSError> {
+        let then = try!(LibSRS2Timestamper::decode_10bit(ts));
+        let age = (now as i32 + 1024 - then as i32) % 1024;
+        if age > self.max_valid_delta as i32 {
+            return Err(SRSError::TimestampExpired{ age: age });
         }
         return Ok(());
     }
 
+}
+
+impl<C> Timestamper for LibSRS2Timestamper<C> where C: Clock {
+
+    fn verify_timestamp(&self, ts: &str) -> Result<(), SRSError> {
+        return self.verify_timestamp_at(ts, self.clock.days_10bit());
+    }
+
     fn now_as_timestamp(&self) -> String {
-        let now = Self::now_in_days_10bit();
-        return Self::base32_email_safe_encode_10bit(now);
+        return LibSRS2Timestamper::encode_10bit(self.clock.days_10bit());
     }
 
 }
 
-pub struct Receiver<T> where T: Timestamper {
-    secret_pkey: PKey,
-    pub hostname: Vec<u8>,
-    pub md: MessageDigest,
-    pub timestamper: T,
+/// Allows choosing the timestamp format at runtime
+impl Timestamper for Box<Timestamper + Send + Sync> {
+
+    fn verify_timestamp(&self, ts: &str) -> Result<(), SRSError> {
+        return (**self).verify_timestamp(ts);
+    }
+
+    fn now_as_timestamp(&self) -> String {
+        return (**self).now_as_timestamp();
+    }
+
 }
 
-#[derive(Debug)]
-pub enum ReceiverError {
-    HashVerificationFailed(String),
-    HashingError(ErrorStack),
-    TimestampError(i32),
+/// How the hash over an address is computed and encoded
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum HashFormat {
+    /// The configured digest, case folding and encoding
+    Native,
+    /// Interoperable with libsrs2 / postsrsd: HMAC-SHA1 over the lowercased
+    /// components, standard base64. The configured digest, case folding and
+    /// encoding are ignored.
+    LibSRS2,
 }
 
-#[derive(Debug)]
-pub enum ReceiverInitializationError {
-    HostnameInvalidChars,
+/// Encoding of native hashes
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum HashEncoding {
+    /// 6 bit per char, lost if an MTA changes the case of the address
+    Base64,
+    /// 5 bit per char, case-insensitive
+    Base32,
+}
+
+/// Parameters besides the secret that determine an address' hash
+#[derive(Clone,Copy)]
+struct HashParams<'a> {
+    md: &'a MessageDigest,
+    length: usize,
+    format: HashFormat,
+    fold_case: bool,
+    encoding: HashEncoding,
+}
+
+/// How addresses that already are SRS addresses are forwarded, as in libsrs2
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum SRSScheme {
+    /// SRS0 is wrapped into SRS1 pointing at the previous hop, SRS1 only changes domain.
+    /// Bounces return via the first forwarder, which keeps its hash secret.
+    Guarded,
+    /// SRS0 is replaced by a new SRS0 pointing at the original sender, skipping the previous hop.
+    /// The previous hop's hash is not verified.
+    Shortcut,
+    /// Any address is wrapped into a new SRS0, bounces are reversed hop by hop.
+    /// Addresses grow with every hop.
+    Reversible,
+}
+
+/// Length of the hashes issued by default, 4 base64 chars = 24 bit
+pub const DEFAULT_HASH_LENGTH: usize = 4;
+/// Shortest hash issued or accepted, anything shorter is guessed within a few thousand tries
+pub const MIN_HASH_LENGTH: usize = 4;
+
+/// A `MessageDigest` that can be shared between threads
+#[derive(Clone)]
+struct SharedDigest(MessageDigest);
+
+// MessageDigest only wraps a pointer to one of OpenSSL's static, immutable EVP_MD descriptions
+unsafe impl Send for SharedDigest {}
+unsafe impl Sync for SharedDigest {}
+
+pub struct Receiver<T> where T: Timestamper {
+    secret_pkeys: Vec<PKey>, // newest first
+    pub hostname: Vec<u8>,
+    md: SharedDigest, // set by the constructors, see `md()`
+    pub timestamper: T,
+    /// Length of the hashes our forwarder issues, longer hashes are rejected
+    pub hash_length: usize,
+    /// Shorter hashes are rejected, lower than `hash_length` to keep accepting legacy hashes
+    pub hash_min_length: usize,
+    pub hash_format: HashFormat,
+    /// Hash the lowercased components, for addresses whose case is changed in transit
+    pub fold_case: bool,
+    pub hash_encoding: HashEncoding,
+    /// Must match the forwarding side
+    pub scheme: SRSScheme,
 }
 
-fn is_email_compatible_ascii(substr: &[u8]) -> bool {
-    // TODO
-    // https://tools.ietf.org/html/rfc5321
-    // https://tools.ietf.org/html/rfc822
-    // https://en.wikipedia.org/wiki/Email_address
-    return true;
+/// SRS domains are ASCII, internationalized ones must be given as A-labels
+fn is_email_compatible_ascii(hostname: &[u8]) -> bool {
+    return match str::from_utf8(hostname) {
+        Ok(hostname) => syntax::is_valid_domain(hostname, Charset::ASCII),
+        Err(_)       => false,
+    };
 }
 
-fn is_valid_srs_separator(separator: &str) -> bool {
+pub fn is_valid_srs_separator(separator: &str) -> bool {
     match separator {
         "=" | "+" | "-" => true,
         _               => false,
     }
 }
 
-fn compute_addr_hash(key: &PKey, md: &MessageDigest, address: &SRSAddress) -> Result<String,ErrorStack> {
+/// The hash is truncated to `params.length` chars, at most as many as the HMAC can fill.
+fn compute_addr_hash(key: &PKey, params: HashParams, address: &SRSAddressRef) -> Result<String,ErrorStack> {
+
+    let (md, fold_case, encoding) = match params.format {
+        HashFormat::Native  => (params.md.clone(), params.fold_case, params.encoding),
+        HashFormat::LibSRS2 => (MessageDigest::sha1(), true, HashEncoding::Base64),
+    };
+    let mut signer = try!(Signer::new(md, key));
+
+    // Both formats hash the components in the same order
+    let components: [&str; 3] = match *address {
+        SRSAddressRef::SRS0(a) => [a.tt, a.hostname, a.local],
+        SRSAddressRef::SRS1(a) => [a.hostname, a.opaque_local, ""], // hashing nothing is a no-op
+    };
+    for c in &components {
+        match fold_case {
+            false => try!(signer.update(c.as_bytes())),
+            true  => try!(signer.update(c.to_ascii_lowercase().as_bytes())),
+        }
+    }
 
-    let mut signer = try!(Signer::new(md.clone(), key));
+    let hmac = try!(signer.finish());
 
-    match *address {
-        SRSAddress::SRS0(ref a) => {
-            try!(signer.update(a.tt.as_bytes()));
-            try!(signer.update(a.hostname.as_bytes()));
-            try!(signer.update(a.local.as_bytes()));
+    let mut hmac_prefix = match encoding {
+        HashEncoding::Base64 => {
+            // base64 encodes groups of 3 bytes, so encode enough groups and cut the rest
+            let groups = (params.length + 3) / 4;
+            let nbytes = cmp::min(groups * 3, (hmac.len() / 3) * 3);
+            let encoded = match params.format {
+                HashFormat::Native  => base64_email_safe_encode(&hmac[0..nbytes]),
+                HashFormat::LibSRS2 => base64_standard_encode(&hmac[0..nbytes]),
+            };
+            encoded.expect("length is a multiple of 3")
         },
-        SRSAddress::SRS1(ref a) => {
-            try!(signer.update(a.hostname.as_bytes()));
-            try!(signer.update(a.opaque_local.as_bytes()));
-        },
-    }
+        HashEncoding::Base32 => base32_email_safe_encode(&hmac),
+    };
+    hmac_prefix.truncate(params.length);
 
-    let hmac = try!(signer.finish());
+    return Ok(hmac_prefix);
 
-    let hmac_base64_prefix = base64_email_safe_encode(&hmac[0..3])
-        .expect("caller asserts length multiple of 3");
+}
+
+/// Find the index of the secret the address' hash was made with.
+fn verify_addr_hash(keys: &[PKey], params: HashParams, min_length: usize, address: &SRSAddressRef) -> Result<usize, SRSError> {
 
-    return Ok(hmac_base64_prefix);
+    let hash = address.hash();
+
+    if min_length < MIN_HASH_LENGTH {
+        return Err(SRSError::InvalidHashLength(min_length));
+    }
+    if hash.len() < min_length {
+        return Err(SRSError::HashTooShort{ len: hash.len(), min: min_length });
+    }
+
+    let mut newest_hash = None;
+    for (idx, key) in keys.iter().enumerate() {
+        let expected_hash = match compute_addr_hash(key, params, address) {
+            Err(es) => return Err(SRSError::HashingError(es)),
+            Ok(x) => x,
+        };
+        // Shorter hashes are prefixes of the full length hash
+        if hash.len() <= expected_hash.len() && expected_hash[..hash.len()].eq_ignore_ascii_case(hash) {
+            return Ok(idx);
+        }
+        if newest_hash.is_none() {
+            newest_hash = Some(expected_hash);
+        }
+    }
+
+    // Report the hash the current secret would have produced
+    return Err(SRSError::HashMismatch{ expected: newest_hash.expect("there is at least one secret") });
+}
 
+fn make_secret_pkeys(secrets: Vec<Vec<u8>>) -> Vec<PKey> {
+    // TODO key derivation?
+    return secrets.iter()
+        .map(|s| PKey::hmac(s.as_ref()).unwrap())
+        .collect();
 }
 
 impl<T> Receiver<T> where T: Timestamper {
 
-    pub fn new(secret: Vec<u8>, hostname: Vec<u8>, md: MessageDigest, timestamper: T) -> Result<Receiver<T>, ReceiverInitializationError> {
+    pub fn new(secret: Vec<u8>, hostname: Vec<u8>, md: MessageDigest, timestamper: T) -> Result<Receiver<T>, SRSError> {
+        return Self::with_secrets(vec![secret], hostname, md, timestamper);
+    }
+
+    /// Create a receiver that accepts hashes made with any of `secrets`.
+    /// The list is ordered newest first, matching `Forwarder::with_secrets`.
+    pub fn with_secrets(secrets: Vec<Vec<u8>>, hostname: Vec<u8>, md: MessageDigest, timestamper: T) -> Result<Receiver<T>, SRSError> {
 
+        // Any separator is accepted, the forwarder rejects hostnames containing its own
         if !is_email_compatible_ascii(&hostname) {
-            return Err(ReceiverInitializationError::HostnameInvalidChars);
+            return Err(SRSError::InvalidHostname);
         }
-        // TODO if contains srs separator discard
 
-        // TODO key derivation?
-        // Create a PKey
-        let secret_pkey = PKey::hmac(secret.as_ref()).unwrap();
+        if secrets.is_empty() {
+            return Err(SRSError::NoSecrets);
+        }
 
         return Ok(Receiver{
-            secret_pkey: secret_pkey,
+            secret_pkeys: make_secret_pkeys(secrets),
             hostname: hostname,
-            md: md,
+            md: SharedDigest(md),
             timestamper: timestamper,
+            hash_length: DEFAULT_HASH_LENGTH,
+            hash_min_length: DEFAULT_HASH_LENGTH,
+            hash_format: HashFormat::Native,
+            fold_case: false,
+            hash_encoding: HashEncoding::Base64,
+            scheme: SRSScheme::Guarded,
         });
     }
 
-    pub fn receive(&self, address: &SRSAddress) -> Result<String, ReceiverError> {
+    /// The digest addresses are hashed with
+    pub fn md(&self) -> &MessageDigest {
+        return &self.md.0;
+    }
 
-        let expected_hash = match compute_addr_hash(&self.secret_pkey, &self.md, &address) {
-            Err(es) => return Err(ReceiverError::HashingError(es)),
-            Ok(x) => x,
+    fn hash_params(&self) -> HashParams {
+        return HashParams{
+            md: &self.md.0,
+            length: self.hash_length,
+            format: self.hash_format,
+            fold_case: self.fold_case,
+            encoding: self.hash_encoding,
         };
+    }
 
-        let hash = match address {
-            &SRSAddress::SRS0(ref a) => a.hash.as_str(),
-            &SRSAddress::SRS1(ref a) => a.hash.as_str(),
-        };
-        if !expected_hash.eq_ignore_ascii_case(hash) {
-            return Err(ReceiverError::HashVerificationFailed(expected_hash));
+    /// Find the index of the secret the address' hash was made with.
+    fn verify_hash(&self, address: &SRSAddressRef) -> Result<usize, SRSError> {
+        return verify_addr_hash(&self.secret_pkeys, self.hash_params(), self.hash_min_length, address);
+    }
+
+    pub fn receive(&self, address: &SRSAddress) -> Result<String, SRSError> {
+        return self.receive_ref(&address.borrowed());
+    }
+
+    /// Like `receive`, but also returns the index of the secret that verified the hash.
+    /// An old secret that is no longer reported can be retired.
+    pub fn receive_with_key_index(&self, address: &SRSAddress) -> Result<(String, usize), SRSError> {
+        return self.receive_ref_with_key_index(&address.borrowed());
+    }
+
+    /// Like `receive`, for an address parsed with `SRSAddressRef::from_string`
+    pub fn receive_ref(&self, address: &SRSAddressRef) -> Result<String, SRSError> {
+        return self.receive_ref_with_key_index(address).map(|(rewritten, _)| rewritten);
+    }
+
+    pub fn receive_ref_with_key_index(&self, address: &SRSAddressRef) -> Result<(String, usize), SRSError> {
+
+        if address.is_1() && self.scheme != SRSScheme::Guarded {
+            return Err(SRSError::SchemeMismatch);
         }
 
-        if let SRS0(ref a) = *address {
-            match self.timestamper.verify_timestamp(a.tt.as_str()) {
-                Ok(())      => {},
-                Err(delta)  => {
-                    return Err(ReceiverError::TimestampError(delta));
-                }
-            }
+        let key_index = try!(self.verify_hash(address));
+
+        if let SRSAddressRef::SRS0(a) = *address {
+            try!(self.timestamper.verify_timestamp(a.tt));
         }
 
         return match *address {
-            SRSAddress::SRS0(ref a) => {
+            SRSAddressRef::SRS0(a) => {
                 let mut rewritten = String::with_capacity(a.local.len() + a.hostname.len() + 1);
-                rewritten.push_str(&a.local);
+                rewritten.push_str(a.local);
                 rewritten.push_str("@");
-                rewritten.push_str(&a.hostname);
-                Ok(rewritten)
+                rewritten.push_str(a.hostname);
+                Ok((rewritten, key_index))
             },
-            SRSAddress::SRS1(ref a) => {
+            SRSAddressRef::SRS1(a) => {
                 let mut rewritten = String::with_capacity(4 + a.opaque_local.len() + 1 + a.hostname.len());
                 rewritten.push_str("SRS0");
-                rewritten.push_str(&a.opaque_local); // contains a.hostname's separator
+                rewritten.push_str(a.opaque_local); // contains a.hostname's separator
                 rewritten.push_str("@");
-                rewritten.push_str(&a.hostname);
-                Ok(rewritten)
+                rewritten.push_str(a.hostname);
+                Ok((rewritten, key_index))
             }
         };
     }
@@ -254,24 +615,56 @@ impl<T> Receiver<T> where T: Timestamper {
 
 
 pub struct Forwarder<T> where T: Timestamper {
-    secret_pkey: PKey,
+    secret_pkeys: Vec<PKey>, // newest first, signs with secret_pkeys[0]
     pub hostname: Vec<u8>,
-    pub md: MessageDigest,
+    md: SharedDigest, // set by the constructors, see `md()`
     pub separator: String,
     pub timestamper: T,
+    pub hash_length: usize,
+    /// Shortest hash issued by `OverflowStrategy::Compress`, must be accepted by the receiving side
+    pub hash_min_length: usize,
+    pub hash_format: HashFormat,
+    /// See `Receiver::fold_case`, must match the receiving side
+    pub fold_case: bool,
+    pub hash_encoding: HashEncoding,
+    pub overflow_strategy: OverflowStrategy,
+    pub scheme: SRSScheme,
+    /// Check SRS1 addresses instead of passing them on: those with one of our
+    /// domains must verify, others are signed with our secret like libsrs2 does
+    pub strict: bool,
+    /// Further domains addresses are issued under, the receiver uses the same secrets for all
+    pub own_domains: Vec<String>,
+    /// Senders with other characters are rejected, see `syntax`
+    pub charset: Charset,
 }
 
-#[derive(Debug)]
-pub enum ForwarderInitializationError {
-    HostnameInvalidChars,
-    InvalidSRSSeparator,
+/// RFC 5321 limit on the length of the local part in octets
+pub const MAX_LOCAL_PART_LEN: usize = 64;
+
+/// What the forwarder does if the local part would exceed `MAX_LOCAL_PART_LEN`
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum OverflowStrategy {
+    /// Fail with `SRSError::LocalPartTooLong`
+    Fail,
+    /// Leave the sender to a `DatabaseForwarder`, see `Forwarded::Database`
+    Database,
+    /// Shorten our hash, down to `hash_min_length`
+    Compress,
 }
 
+/// Result of `Forwarder::forward_checked`, reporting which strategy was applied
 #[derive(Debug)]
-pub enum ForwarderError {
-    HashingError(ErrorStack),
+pub enum Forwarded {
+    /// The address fits as is
+    Fits(SRSAddress),
+    /// The address fits after applying `OverflowStrategy::Compress`
+    Compressed(SRSAddress),
+    /// The address would be this long, `OverflowStrategy::Database` asks the
+    /// caller to store the sender with a `DatabaseForwarder` instead
+    Database(usize),
 }
 
+/// See `envelope::parse_envelope_sender`
 #[derive(Debug)]
 pub enum ForwardableAddress {
     SRS(SRSAddress),
@@ -279,34 +672,105 @@ pub enum ForwardableAddress {
         local: String,
         domain: String
     },
+    /// `MAIL FROM:<>`, used by bounces, which must not be rewritten
+    Null,
+}
+
+/// The address without angle brackets, empty for the null sender
+impl fmt::Display for ForwardableAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ForwardableAddress::SRS(ref a)                    => a.fmt(f),
+            ForwardableAddress::Plain{ ref local, ref domain } => write!(f, "{}@{}", local, domain),
+            ForwardableAddress::Null                          => Ok(()),
+        }
+    }
 }
 
 impl<T> Forwarder<T> where T: Timestamper {
 
-    pub fn new(secret: Vec<u8>, hostname: Vec<u8>, md: MessageDigest, separator: &str, timestamper: T) -> Result<Forwarder<T>,ForwarderInitializationError> {
+    pub fn new(secret: Vec<u8>, hostname: Vec<u8>, md: MessageDigest, separator: &str, timestamper: T) -> Result<Forwarder<T>,SRSError> {
+        return Self::with_secrets(vec![secret], hostname, md, separator, timestamper);
+    }
+
+    /// Create a forwarder from an ordered list of secrets, newest first.
+    /// Addresses are always signed with the newest secret.
+    pub fn with_secrets(secrets: Vec<Vec<u8>>, hostname: Vec<u8>, md: MessageDigest, separator: &str, timestamper: T) -> Result<Forwarder<T>,SRSError> {
 
         if !is_email_compatible_ascii(&hostname) {
-            return Err(ForwarderInitializationError::HostnameInvalidChars);
+            return Err(SRSError::InvalidHostname);
         }
 
         if !is_valid_srs_separator(separator) {
-            return Err(ForwarderInitializationError::InvalidSRSSeparator);
+            return Err(SRSError::InvalidSeparator);
+        }
+
+        // SRS1 addresses carry our hostname as a separator-delimited component
+        if hostname.contains(&separator.as_bytes()[0]) {
+            return Err(SRSError::InvalidHostname);
         }
 
-        let secret_key = PKey::hmac(secret.as_ref()).unwrap();
+        if secrets.is_empty() {
+            return Err(SRSError::NoSecrets);
+        }
 
         return Ok(Forwarder{
             separator: separator.to_string(),
-            secret_pkey: secret_key,
+            secret_pkeys: make_secret_pkeys(secrets),
             hostname: hostname,
-            md: md,
+            md: SharedDigest(md),
             timestamper: timestamper,
+            hash_length: DEFAULT_HASH_LENGTH,
+            hash_min_length: DEFAULT_HASH_LENGTH,
+            hash_format: HashFormat::Native,
+            fold_case: false,
+            hash_encoding: HashEncoding::Base64,
+            overflow_strategy: OverflowStrategy::Fail,
+            scheme: SRSScheme::Guarded,
+            strict: false,
+            own_domains: Vec::new(),
+            charset: Charset::ASCII,
         });
     }
 
-    fn update_hash(&self, address: &mut SRSAddress) -> Result<(), ForwarderError> {
-        let hash = match compute_addr_hash(&self.secret_pkey, &self.md, &address) {
-            Err(es) => return Err(ForwarderError::HashingError(es)),
+    /// The digest addresses are hashed with
+    pub fn md(&self) -> &MessageDigest {
+        return &self.md.0;
+    }
+
+    fn hash_params(&self) -> HashParams {
+        return HashParams{
+            md: &self.md.0,
+            length: self.hash_length,
+            format: self.hash_format,
+            fold_case: self.fold_case,
+            encoding: self.hash_encoding,
+        };
+    }
+
+    fn is_own_domain(&self, domain: &str) -> bool {
+        return domain.as_bytes().eq_ignore_ascii_case(&self.hostname)
+            || self.own_domains.iter().any(|d| d.eq_ignore_ascii_case(domain));
+    }
+
+    /// Local parts must be a dot-atom, a quoted string would end up inside the
+    /// dot-atom of our local part. Domains may be address literals.
+    fn check_sender(&self, local: &str, domain: &str) -> Result<(), SRSError> {
+        let valid_domain = syntax::is_valid_domain(domain, self.charset) || syntax::is_address_literal(domain);
+        if !syntax::is_valid_local_part(local, self.charset) || !valid_domain {
+            return Err(SRSError::InvalidSender);
+        }
+        // Would need the whole SRS local part quoted, which many MTAs mishandle
+        if !syntax::is_dot_atom(local, self.charset) {
+            return Err(SRSError::QuotedLocalPart);
+        }
+        return Ok(());
+    }
+
+    fn update_hash(&self, address: &mut SRSAddress, length: usize) -> Result<(), SRSError> {
+        let params = HashParams{ length: length, .. self.hash_params() };
+        let hash = match compute_addr_hash(&self.secret_pkeys[0], params, &address.borrowed()) {
+            Err(es) => return Err(SRSError::HashingError(es)),
             Ok(x) => x,
         };
         match *address {
@@ -316,15 +780,61 @@ impl<T> Forwarder<T> where T: Timestamper {
         return Ok(());
     }
 
-    pub fn forward(&self, address: ForwardableAddress) -> Result<SRSAddress,ForwarderError> {
+    /// Like `forward_checked`, but `OverflowStrategy::Database` fails like `Fail`
+    pub fn forward(&self, address: ForwardableAddress) -> Result<SRSAddress,SRSError> {
+        return match try!(self.forward_checked(address)) {
+            Forwarded::Fits(a) | Forwarded::Compressed(a) => Ok(a),
+            Forwarded::Database(len)                      => Err(SRSError::LocalPartTooLong(len)),
+        };
+    }
 
+    /// Rewrite `address`, applying `overflow_strategy` if the local part would
+    /// exceed `MAX_LOCAL_PART_LEN`.
+    pub fn forward_checked(&self, address: ForwardableAddress) -> Result<Forwarded,SRSError> {
         let hostname = String::from_utf8(self.hostname.clone())
             .expect("should be valid utf8, be checked at compile time");
+        return self.forward_checked_as(address, &hostname);
+    }
 
-        use self::ForwardableAddress::{SRS,Plain};
+    /// Like `forward_checked`, issuing the address under `hostname` instead of ours.
+    /// Add it to `own_domains` for SRS1 addresses to be checked like ours.
+    pub fn forward_checked_as(&self, address: ForwardableAddress, hostname: &str) -> Result<Forwarded,SRSError> {
+
+        if !is_email_compatible_ascii(hostname.as_bytes()) || hostname.contains(self.separator.as_str()) {
+            return Err(SRSError::InvalidHostname);
+        }
+        let shortest = cmp::min(self.hash_length, self.hash_min_length);
+        if shortest < MIN_HASH_LENGTH {
+            return Err(SRSError::InvalidHashLength(shortest));
+        }
+        let hostname = hostname.to_string();
+
+        use self::ForwardableAddress::{SRS,Plain,Null};
+
+        // Shortcut and Reversible only issue SRS0: they forward SRS addresses like
+        // plain ones and differ in the sender the new SRS0 points back to
+        let address = match (self.scheme, address) {
+            (SRSScheme::Shortcut, SRS(SRS0(srs0))) => Plain{ local: srs0.local, domain: srs0.hostname },
+            (SRSScheme::Shortcut, SRS(srs))        |
+            (SRSScheme::Reversible, SRS(srs))      => Plain{ local: srs.local_part(), domain: srs.domain().to_string() },
+            (_, address)                           => address,
+        };
+
+        match address {
+            Plain{ ref local, ref domain } => try!(self.check_sender(local, domain)),
+            SRS(ref srs)                   => try!(self.check_sender(&srs.local_part(), srs.domain())),
+            Null                           => {},
+        }
+
+        let reforwarded = match address {
+            SRS(SRS1(_)) => !self.strict,
+            _            => false,
+        };
         let rewritten: SRSAddress = match address {
+            Null => return Err(SRSError::NullSender),
             Plain{local, domain} => {
                 let mut srs0 = SRS0(SRS0Address{
+                    prefix: "SRS0".to_string(),
                     separator: self.separator.clone(),
                     hash: "".to_string(), // updated below
                     tt: self.timestamper.now_as_timestamp(),
@@ -332,7 +842,7 @@ impl<T> Forwarder<T> where T: Timestamper {
                     local: local,
                     domain: hostname,
                 });
-                try!(self.update_hash(&mut srs0));
+                try!(self.update_hash(&mut srs0, self.hash_length));
                 srs0
             },
             SRS(SRS0(srs0)) => {
@@ -343,23 +853,56 @@ impl<T> Forwarder<T> where T: Timestamper {
                                            srs0.hostname, srs0.separator,
                                            srs0.local);
                 let mut srs1 = SRS1(SRS1Address{
+                    prefix: "SRS1".to_string(),
                     separator: self.separator.clone(),
                     hash: "".to_string(), // updated below
                     hostname: srs0.domain,
                     opaque_local: opaque_local,
                     domain: hostname,
                 });
-                try!(self.update_hash(&mut srs1));
+                try!(self.update_hash(&mut srs1, self.hash_length));
                 srs1
             },
             SRS(SRS1(srs1)) => {
-                let mut srs1 = srs1.clone();
-                srs1.domain = hostname;
-                SRS1(srs1)
+                let own = self.is_own_domain(&srs1.domain);
+                let mut srs1 = SRS1(srs1);
+                if self.strict && own {
+                    match verify_addr_hash(&self.secret_pkeys, self.hash_params(), self.hash_min_length, &srs1.borrowed()) {
+                        Ok(_)                            => {},
+                        Err(SRSError::HashingError(es)) => return Err(SRSError::HashingError(es)),
+                        Err(_)                           => return Err(SRSError::ForgedSRS1),
+                    }
+                } else if self.strict {
+                    try!(self.update_hash(&mut srs1, self.hash_length));
+                }
+                if let SRS1(ref mut a) = srs1 {
+                    a.domain = hostname;
+                }
+                srs1
             },
         };
 
-        return Ok(rewritten);
+        let len = rewritten.local_part_len();
+        if len <= MAX_LOCAL_PART_LEN {
+            return Ok(Forwarded::Fits(rewritten));
+        }
+
+        return match self.overflow_strategy {
+            OverflowStrategy::Fail     => Err(SRSError::LocalPartTooLong(len)),
+            OverflowStrategy::Database => Ok(Forwarded::Database(len)),
+            OverflowStrategy::Compress => {
+                // Only hashes made here can be shortened, an SRS1 address
+                // passing through keeps the hash of the host that issued it
+                let excess = len - MAX_LOCAL_PART_LEN;
+                if !reforwarded && self.hash_length >= self.hash_min_length + excess {
+                    let mut rewritten = rewritten;
+                    try!(self.update_hash(&mut rewritten, self.hash_length - excess));
+                    Ok(Forwarded::Compressed(rewritten))
+                } else {
+                    Err(SRSError::LocalPartTooLong(len))
+                }
+            },
+        };
     }
 
 }
@@ -5,8 +5,39 @@ pub enum Base64Err {
 }
 
 pub fn base64_email_safe_encode(b: &[u8]) -> Result<String, Base64Err> {
+    return base64_encode_with_alphabet(b, b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_");
+}
+
+/// RFC 4648 base64 without padding, as used by libsrs2
+pub fn base64_standard_encode(b: &[u8]) -> Result<String, Base64Err> {
+    return base64_encode_with_alphabet(b, b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/");
+}
+
+/// RFC 4648 base32 in lowercase without padding, a trailing partial group is
+/// filled with zero bits. Survives MTAs that change the case of addresses.
+pub fn base32_email_safe_encode(b: &[u8]) -> String {
+
+    let base32 = b"abcdefghijklmnopqrstuvwxyz234567";
+
+    let mut s = String::with_capacity((b.len() * 8 + 4) / 5);
+    let mut buf: u16 = 0;
+    let mut bits = 0;
+    for &byte in b {
+        buf = (buf << 8) | byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            s.push(base32[((buf >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        s.push(base32[((buf << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    return s;
+}
 
-    let base64 = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_".as_bytes();
+fn base64_encode_with_alphabet(b: &[u8], base64: &[u8]) -> Result<String, Base64Err> {
 
     let blen = b.len();
 
@@ -86,7 +117,7 @@ pub fn base64_email_safe_decode(s: &str) -> Result<Vec<u8>, Base64Err> {
 #[cfg(test)]
 mod test {
 
-    use super::{base64_email_safe_encode,base64_email_safe_decode};
+    use super::{base64_email_safe_encode,base64_email_safe_decode,base64_standard_encode,base32_email_safe_encode};
 
     #[test]
     fn test_base64() {
@@ -100,6 +131,26 @@ mod test {
         assert!(e("") == "");
     }
 
+    #[test]
+    fn it_base64_encodes_with_the_standard_alphabet() {
+        let b = [0xfb, 0xef, 0xff];
+        assert!(base64_email_safe_encode(&b).unwrap() == "--__");
+        assert!(base64_standard_encode(&b).unwrap() == "++//");
+    }
+
+    #[test]
+    fn it_base32_encodes() {
+        // RFC 4648 test vectors, lowercase and unpadded
+        let e = |x: &str| base32_email_safe_encode(x.as_bytes());
+        assert!(e("") == "");
+        assert!(e("f") == "my");
+        assert!(e("fo") == "mzxq");
+        assert!(e("foo") == "mzxw6");
+        assert!(e("foob") == "mzxw6yq");
+        assert!(e("fooba") == "mzxw6ytb");
+        assert!(e("foobar") == "mzxw6ytboi");
+    }
+
     #[test]
     fn it_base64_encodes_only_supports_lengths_multiples_of_3() {
         let r = base64_email_safe_encode("four".as_bytes());